@@ -0,0 +1,94 @@
+//! Per-keystroke overhead of the keyboard monitors' event path.
+//!
+//! Run with `cargo bench --bench keystroke`. "before" is the old path, a
+//! cloned device id sent over an unbounded channel for every press; "after"
+//! is `Activity::key_pressed`, both while typing on the active keyboard and
+//! while another keyboard is taking over.
+
+#[allow(dead_code)]
+#[path = "../src/activity.rs"]
+mod activity;
+
+use std::{
+    hint::black_box,
+    time::Instant,
+};
+
+use activity::{
+    Activity,
+    DeviceSlot,
+};
+use tokio::sync::mpsc;
+
+const PRESSES: u32 = 1_000_000;
+
+fn report(name: &str, started: Instant) {
+    let per_press = started.elapsed().as_nanos() as f64 / f64::from(PRESSES);
+    println!("{:<40} {:>8.1} ns/keystroke", name, per_press);
+}
+
+fn before() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+        let device_id = "046d:c52b".to_string();
+
+        let started = Instant::now();
+        let consumer = tokio::spawn(async move {
+            let mut received = 0;
+            while let Some(id) = rx.recv().await {
+                black_box(id);
+                received += 1;
+                if received == PRESSES {
+                    break;
+                }
+            }
+        });
+        for _ in 0..PRESSES {
+            tx.send(black_box(device_id.clone())).unwrap();
+        }
+        consumer.await.unwrap();
+        report("before: String over unbounded channel", started);
+    });
+}
+
+fn after_active() {
+    let activity = Activity::default();
+    let slot = DeviceSlot::default();
+    activity.set_active(Some(0));
+
+    let started = Instant::now();
+    for _ in 0..PRESSES {
+        black_box(activity.key_pressed(black_box(0), &slot));
+    }
+    report("after: typing on the active keyboard", started);
+}
+
+fn after_takeover() {
+    let activity = Activity::default();
+    let slot = DeviceSlot::default();
+    activity.set_active(Some(0));
+
+    // Every press lands on keyboard 1; the loop drains it every 8 presses
+    let (tx, mut rx) = mpsc::channel(activity::CHANNEL_CAPACITY);
+    let started = Instant::now();
+    for i in 0..PRESSES {
+        if activity.key_pressed(1, &slot) && tx.try_send(1u32).is_err() {
+            slot.clear();
+        }
+        if i % 8 == 7 {
+            while let Ok(index) = rx.try_recv() {
+                black_box((index, activity.take(&slot)));
+            }
+        }
+    }
+    report("after: typing on another keyboard", started);
+}
+
+fn main() {
+    before();
+    after_active();
+    after_takeover();
+}
@@ -10,23 +10,29 @@ cargo build --release
 sudo cp target/release/kunai /usr/local/bin/
 echo "✓ Binary installed to /usr/local/bin/kunai"
 
-# Install udev rule for keyboard access
-echo "Setting up keyboard access permissions..."
-cat > /tmp/99-kunai.rules << 'EOF'
+if [ "$1" = "--helper" ]; then
+    # Read keyboards from a root helper instead of granting the user access
+    sudo /usr/local/bin/kunai service install --helper
+    echo "✓ Input helper installed"
+else
+    # Install udev rule for keyboard access
+    echo "Setting up keyboard access permissions..."
+    cat > /tmp/99-kunai.rules << 'EOF'
 # Allow users to access keyboard input devices for kunai
 KERNEL=="event*", SUBSYSTEM=="input", ENV{ID_INPUT_KEYBOARD}=="1", TAG+="uaccess"
 EOF
 
-sudo cp /tmp/99-kunai.rules /etc/udev/rules.d/
-echo "✓ Udev rule installed"
+    sudo cp /tmp/99-kunai.rules /etc/udev/rules.d/
+    echo "✓ Udev rule installed"
 
-# Reload udev rules and trigger for existing devices
-sudo udevadm control --reload-rules
-sudo udevadm trigger --subsystem-match=input
-echo "✓ Udev rules reloaded (no logout required)"
+    # Reload udev rules and trigger for existing devices
+    sudo udevadm control --reload-rules
+    sudo udevadm trigger --subsystem-match=input
+    echo "✓ Udev rules reloaded (no logout required)"
 
-# Wait a moment for udev to apply
-sleep 1
+    # Wait a moment for udev to apply
+    sleep 1
+fi
 
 echo ""
 echo "Installation complete!"
@@ -37,3 +43,7 @@ echo "  2. Run: kunai setup"
 echo "  3. Run: kunai daemon --dry-run"
 echo "  4. Add to niri config:"
 echo "     spawn-at-startup command \"kunai\" \"daemon\""
+if [ "$1" = "--helper" ]; then
+    echo "  5. Add to ~/.config/kunai/config.toml:"
+    echo "     input_helper = \"/run/kunai/helper.sock\""
+fi
@@ -0,0 +1,154 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{
+            AtomicU32,
+            AtomicU64,
+            Ordering,
+        },
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+/// Small integer standing in for a "vid:pid" device id on the hot path
+pub type DeviceIndex = u32;
+
+/// How many keyboards can have a wake-up queued at once. Each keyboard has
+/// at most one in flight, so this only fills up with absurd setups.
+pub const CHANNEL_CAPACITY: usize = 64;
+
+const NO_DEVICE: u32 = u32::MAX;
+const NEVER: u64 = u64::MAX;
+
+/// Key presses from one keyboard that the daemon loop hasn't looked at yet
+#[derive(Default)]
+pub struct DeviceSlot {
+    pending:       AtomicU32,
+    last_press_ms: AtomicU64,
+}
+
+impl DeviceSlot {
+    /// Forget queued presses, e.g. when the keyboard is (re)connected or its
+    /// wake-up could not be queued.
+    pub fn clear(&self) {
+        self.pending.store(0, Ordering::Release);
+    }
+}
+
+/// Key press state shared between the keyboard monitors and the daemon loop.
+///
+/// Presses on the active keyboard only bump timestamps here. A monitor wakes
+/// the daemon loop only for the first unread press on any other keyboard, so
+/// typing costs a few atomic operations instead of a channel message each.
+pub struct Activity {
+    start:  Instant,
+    active: AtomicU32,
+
+    active_last_key_ms: AtomicU64,
+    last_key_ms:        AtomicU64,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            active: AtomicU32::new(NO_DEVICE),
+            active_last_key_ms: AtomicU64::new(NEVER),
+            last_key_ms: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Activity {
+    fn now_ms(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    fn instant(&self, ms: u64) -> Instant {
+        self.start + Duration::from_millis(ms)
+    }
+
+    /// Record a key press from keyboard `index`. Returns true if the daemon
+    /// loop has to be woken up for it.
+    pub fn key_pressed(&self, index: DeviceIndex, slot: &DeviceSlot) -> bool {
+        let now = self.now_ms();
+        self.last_key_ms.fetch_max(now, Ordering::Relaxed);
+
+        if self.active.load(Ordering::Acquire) == index {
+            self.active_last_key_ms.store(now, Ordering::Relaxed);
+            return false;
+        }
+
+        slot.last_press_ms.store(now, Ordering::Relaxed);
+        slot.pending.fetch_add(1, Ordering::AcqRel) == 0
+    }
+
+    /// Take the presses queued in `slot`: how many, and when the last was.
+    pub fn take(&self, slot: &DeviceSlot) -> (u32, Instant) {
+        let presses = slot.pending.swap(0, Ordering::AcqRel);
+        (
+            presses,
+            self.instant(slot.last_press_ms.load(Ordering::Relaxed)),
+        )
+    }
+
+    /// Tell the monitors which keyboard is active, so they stop waking the
+    /// daemon loop for it.
+    pub fn set_active(&self, index: Option<DeviceIndex>) {
+        let index = index.unwrap_or(NO_DEVICE);
+        if self.active.swap(index, Ordering::AcqRel) != index {
+            self.active_last_key_ms.store(NEVER, Ordering::Relaxed);
+        }
+    }
+
+    /// Last key press on the active keyboard since it became active.
+    pub fn active_last_key(&self) -> Option<Instant> {
+        match self.active_last_key_ms.load(Ordering::Relaxed) {
+            NEVER => None,
+            ms => Some(self.instant(ms)),
+        }
+    }
+
+    /// Last key press on any keyboard.
+    pub fn last_key(&self) -> Instant {
+        self.instant(self.last_key_ms.load(Ordering::Relaxed))
+    }
+}
+
+/// Maps "vid:pid" device ids to stable indices for as long as the daemon
+/// runs, so a keyboard keeps its index across reconnects.
+#[derive(Default)]
+pub struct Interner {
+    ids:   Vec<String>,
+    slots: Vec<Arc<DeviceSlot>>,
+    index: HashMap<String, DeviceIndex>,
+}
+
+impl Interner {
+    pub fn intern(&mut self, device_id: &str) -> DeviceIndex {
+        if let Some(index) = self.index.get(device_id) {
+            return *index;
+        }
+        let index = self.ids.len() as DeviceIndex;
+        self.ids.push(device_id.to_string());
+        self.slots.push(Arc::default());
+        self.index.insert(device_id.to_string(), index);
+        index
+    }
+
+    pub fn get(&self, device_id: &str) -> Option<DeviceIndex> {
+        self.index.get(device_id).copied()
+    }
+
+    pub fn device_id(&self, index: DeviceIndex) -> &str {
+        &self.ids[index as usize]
+    }
+
+    pub fn slot(&self, index: DeviceIndex) -> &Arc<DeviceSlot> {
+        &self.slots[index as usize]
+    }
+}
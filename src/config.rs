@@ -1,4 +1,5 @@
 use std::{
+    collections::BTreeMap,
     fs,
     path::PathBuf,
 };
@@ -9,24 +10,282 @@ use serde::{
     Serialize,
 };
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Config {
-    pub keyboards: Vec<KeyboardConfig>,
+    #[serde(default)]
+    pub keyboards:         Vec<KeyboardConfig>,
+    /// Layout for keyboards that have no entry in `keyboards`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_layout:    Option<u32>,
+    /// Revert to `idle_layout` after this many seconds without key presses.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_timeout_secs: Option<u64>,
+    /// Layout to revert to when idle. Defaults to `default_layout`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_layout:       Option<u32>,
+
+    /// Remember manual layout switches for the keyboard they were made on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sticky: Option<StickyMode>,
+
+    /// Logical languages, switched with `kunai language`. The first one is
+    /// active at startup.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub languages: Vec<String>,
+
+    /// Layouts for specific applications, matched on niri's focused window.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub app_rules:           Vec<AppRule>,
+    #[serde(default)]
+    pub app_rule_mode:       RuleMode,
+    /// Layouts for workspaces (by name or index) and outputs.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub workspace_rules:     Vec<WorkspaceRule>,
+    #[serde(default)]
+    pub workspace_rule_mode: RuleMode,
+
+    /// Restore the layout each window last used when it regains focus.
+    #[serde(default)]
+    pub remember_window_layouts: bool,
+
+    #[serde(default)]
+    pub policy:       PolicyConfig,
+    #[serde(default)]
+    pub script:       ScriptConfig,
+    /// Read keyboards through the `kunai helper` listening on this socket
+    /// instead of opening them directly.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_helper: Option<PathBuf>,
+    #[serde(default)]
+    pub sandbox:      SandboxConfig,
+    /// Commands to run on layout switches, keyboard (dis)connects and pauses.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hooks:        Vec<HookConfig>,
+}
+
+/// Use `layout_index` while a window matching `app_id` and/or `title` is
+/// focused.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppRule {
+    /// Exact app_id, e.g. "kitty"
+    pub app_id:       Option<String>,
+    /// Substring of the window title
+    pub title:        Option<String>,
+    pub layout_index: u32,
+}
+
+impl AppRule {
+    pub fn matches(&self, app_id: Option<&str>, title: Option<&str>) -> bool {
+        if self.app_id.is_none() && self.title.is_none() {
+            return false;
+        }
+        let app_matches = self
+            .app_id
+            .as_deref()
+            .is_none_or(|want| app_id == Some(want));
+        let title_matches = self
+            .title
+            .as_deref()
+            .is_none_or(|want| title.is_some_and(|t| t.contains(want)));
+        app_matches && title_matches
+    }
 }
 
+/// Use `layout_index` while the focused workspace matches every field that
+/// is set.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkspaceRule {
+    /// Workspace name, as in niri's `workspace "name"`
+    pub name:         Option<String>,
+    /// Workspace index on its output, starting at 1
+    pub index:        Option<u8>,
+    /// Output (monitor) name, e.g. "DP-1"
+    pub output:       Option<String>,
+    pub layout_index: u32,
+}
+
+impl WorkspaceRule {
+    pub fn matches(&self, name: Option<&str>, index: u8, output: Option<&str>) -> bool {
+        if self.name.is_none() && self.index.is_none() && self.output.is_none() {
+            return false;
+        }
+        self.name.as_deref().is_none_or(|want| name == Some(want))
+            && self.index.is_none_or(|want| index == want)
+            && self
+                .output
+                .as_deref()
+                .is_none_or(|want| output == Some(want))
+    }
+}
+
+/// How app/workspace rules and keyboard mappings are combined.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleMode {
+    /// The rule's layout is kept while it matches, whichever keyboard is
+    /// typed on
+    #[default]
+    Override,
+    /// The rule's layout is applied when its window or workspace gains
+    /// focus; switching keyboards afterwards changes it as usual
+    OnFocus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StickyMode {
+    /// Keep learned layouts until the daemon exits
+    Session,
+    /// Also write learned layouts back to config.toml
+    Persist,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct KeyboardConfig {
     pub name:         String,
     pub vendor_id:    String,
     pub product_id:   String,
+    /// Preferred layout, used when the keyboard becomes active.
     pub layout_index: u32,
+
+    /// Layouts `kunai cycle` steps through while this keyboard is active.
+    /// Empty means only `layout_index`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub layouts:          Vec<u32>,
+    /// Layout to use for each logical language on this keyboard.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub language_layouts: BTreeMap<String, u32>,
+    /// Keyboards with a higher priority take over after `min_keystrokes`,
+    /// ignoring the hysteresis window of a lower-priority active keyboard.
+    #[serde(default)]
+    pub priority:         i32,
+
+    /// Switch to this keyboard's layout as soon as it is plugged in.
+    #[serde(default)]
+    pub activate_on_connect:   bool,
+    /// When this keyboard is unplugged while active, switch back to the
+    /// layout of the keyboard that was active before it.
+    #[serde(default)]
+    pub restore_on_disconnect: bool,
+}
+
+impl KeyboardConfig {
+    /// The "vid:pid" key used to identify this keyboard at runtime.
+    pub fn device_id(&self) -> String {
+        format!("{}:{}", self.vendor_id, self.product_id)
+    }
+
+    /// The layouts this keyboard may cycle through, always including the
+    /// preferred one.
+    pub fn allowed_layouts(&self) -> Vec<u32> {
+        let mut layouts = self.layouts.clone();
+        if !layouts.contains(&self.layout_index) {
+            layouts.insert(0, self.layout_index);
+        }
+        layouts
+    }
+}
+
+/// When to switch layouts as key presses arrive from different keyboards.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PolicyConfig {
+    /// Minimum time between two switches.
+    pub debounce_ms:    u64,
+    /// Stay on the active keyboard for this long after its last key press.
+    pub hysteresis_ms:  u64,
+    /// Consecutive key presses needed on another keyboard before switching.
+    pub min_keystrokes: u32,
+}
+
+impl Default for PolicyConfig {
+    fn default() -> Self {
+        Self {
+            debounce_ms:    100,
+            hysteresis_ms:  0,
+            min_keystrokes: 1,
+        }
+    }
+}
+
+/// A Rhai script that has the final say on every switch decision.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScriptConfig {
+    /// Script defining `fn decide(ctx)`. No script means the built-in rules.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path:      Option<PathBuf>,
+    /// Time a single evaluation may take before the built-in decision is used.
+    pub budget_ms: u64,
+    /// Append each decision's context to `events.jsonl` for `kunai rules test`.
+    pub record:    bool,
+}
+
+impl Default for ScriptConfig {
+    fn default() -> Self {
+        Self {
+            path:      None,
+            budget_ms: 5,
+            record:    false,
+        }
+    }
+}
+
+/// What a hook runs on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookEvent {
+    /// niri switched to the layout kunai asked for
+    Switch,
+    Connect,
+    Disconnect,
+    Pause,
+    Resume,
+}
+
+/// A shell command to run when `event` happens.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HookConfig {
+    pub event:           HookEvent,
+    /// Run with `sh -c`
+    pub command:         String,
+    /// Only for this keyboard, by "vid:pid" or name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keyboard:        Option<String>,
+    /// Kill the command if it runs longer than this.
+    #[serde(default = "default_hook_timeout_ms")]
+    pub timeout_ms:      u64,
+    /// Run at most once per this interval; events in between are combined
+    /// into one run with the latest values.
+    #[serde(default)]
+    pub min_interval_ms: u64,
+}
+
+fn default_hook_timeout_ms() -> u64 {
+    5000
+}
+
+/// Opt-in hardening of the daemon process.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SandboxConfig {
+    /// Restrict filesystem access with Landlock and syscalls with seccomp,
+    /// and set `no_new_privs`. Hooks and `niri msg` inherit both, so they
+    /// only get the daemon's own syscalls and no network sockets.
+    pub enabled: bool,
 }
 
 impl Config {
+    /// True if there is nothing for the daemon to do: no keyboards and no
+    /// default layout.
+    pub fn is_empty(&self) -> bool {
+        self.keyboards.is_empty() && self.default_layout.is_none()
+    }
+
     pub fn load() -> Result<Self> {
         let config_path = get_config_path()?;
         if !config_path.exists() {
-            return Ok(Config { keyboards: vec![] });
+            return Ok(Config::default());
         }
         let data = fs::read_to_string(&config_path)?;
         Ok(toml::from_str(&data)?)
@@ -40,9 +299,36 @@ impl Config {
         fs::write(&config_path, toml::to_string(self)?)?;
         Ok(())
     }
+
+    /// Set the layout of the configured keyboard `device_id`, or its layout
+    /// for `language`, and save the config. Returns false if the keyboard is
+    /// not configured.
+    pub fn persist_layout(
+        device_id: &str,
+        language: Option<&str>,
+        layout_index: u32,
+    ) -> Result<bool> {
+        let mut config = Self::load()?;
+        let Some(kb) = config
+            .keyboards
+            .iter_mut()
+            .find(|kb| kb.device_id() == device_id)
+        else {
+            return Ok(false);
+        };
+        match language {
+            Some(language) => {
+                kb.language_layouts
+                    .insert(language.to_string(), layout_index);
+            }
+            None => kb.layout_index = layout_index,
+        }
+        config.save()?;
+        Ok(true)
+    }
 }
 
-fn get_config_path() -> Result<PathBuf> {
+pub fn get_config_path() -> Result<PathBuf> {
     let config_dir =
         dirs::config_dir().ok_or_else(|| anyhow::anyhow!("Could not find config directory"))?;
     Ok(config_dir.join("kunai").join("config.toml"))
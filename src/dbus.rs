@@ -0,0 +1,362 @@
+use anyhow::Result;
+use tokio::sync::{
+    mpsc,
+    oneshot,
+    watch,
+};
+use tracing::debug;
+use zbus::{
+    connection,
+    fdo,
+    interface,
+    object_server::SignalEmitter,
+};
+
+use crate::{
+    instance,
+    ipc::{
+        self,
+        Snapshot,
+    },
+};
+
+const PATH: &str = "/org/kunai/Daemon1";
+const NAME: &str = "org.kunai.Daemon1";
+
+/// The daemon's name on the session bus: `org.kunai.Daemon1`, or
+/// `org.kunai.Daemon1.<instance>` for other instances.
+pub fn bus_name() -> String {
+    match instance::name() {
+        instance::DEFAULT_INSTANCE => NAME.to_string(),
+        // Bus name elements must not start with a digit
+        name if name.starts_with(|c: char| c.is_ascii_digit()) => format!("{}._{}", NAME, name),
+        name => format!("{}.{}", NAME, name),
+    }
+}
+
+/// The `org.kunai.Daemon1` object. Properties mirror the latest snapshot;
+/// methods are handled by the main loop like control socket requests.
+struct Daemon {
+    requests: mpsc::UnboundedSender<ipc::Envelope>,
+    snapshot: Snapshot,
+}
+
+impl Daemon {
+    async fn request(&self, request: ipc::Request) -> fdo::Result<()> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.requests
+            .send((request, reply_tx))
+            .map_err(|_| fdo::Error::Failed("Daemon is shutting down".to_string()))?;
+        match reply_rx.await {
+            Ok(ipc::Response::Ok) => Ok(()),
+            Ok(ipc::Response::Error { message }) => Err(fdo::Error::Failed(message)),
+            Ok(other) => Err(fdo::Error::Failed(format!(
+                "Unexpected response: {:?}",
+                other
+            ))),
+            Err(_) => Err(fdo::Error::Failed("Daemon is shutting down".to_string())),
+        }
+    }
+}
+
+#[interface(name = "org.kunai.Daemon1")]
+impl Daemon {
+    /// Keyboard last typed on, as ("vid:pid", name); empty strings if none
+    #[zbus(property)]
+    fn active_keyboard(&self) -> (String, String) {
+        self.snapshot
+            .active
+            .as_ref()
+            .map(|kb| (kb.device_id.clone(), kb.name.clone()))
+            .unwrap_or_default()
+    }
+
+    /// Current layout as (index, name); -1 and "" if not known yet
+    #[zbus(property)]
+    fn current_layout(&self) -> (i32, String) {
+        (
+            self.snapshot.layout_index.map_or(-1, |index| index as i32),
+            self.snapshot.layout.clone().unwrap_or_default(),
+        )
+    }
+
+    /// Connected keyboards being monitored, as ("vid:pid", name)
+    #[zbus(property)]
+    fn keyboards(&self) -> Vec<(String, String)> {
+        self.snapshot
+            .keyboards
+            .iter()
+            .map(|kb| (kb.device_id.clone(), kb.name.clone()))
+            .collect()
+    }
+
+    #[zbus(property)]
+    fn paused(&self) -> bool {
+        self.snapshot.paused
+    }
+
+    /// Stop switching layouts; resume automatically after `timeout_secs`
+    /// unless it is 0
+    async fn pause(&self, timeout_secs: u64) -> fdo::Result<()> {
+        self.request(ipc::Request::Pause {
+            timeout_secs: (timeout_secs > 0).then_some(timeout_secs),
+        })
+        .await
+    }
+
+    async fn resume(&self) -> fdo::Result<()> {
+        self.request(ipc::Request::Resume).await
+    }
+
+    /// Re-read config.toml
+    async fn reload(&self) -> fdo::Result<()> {
+        self.request(ipc::Request::Reload).await
+    }
+
+    /// Use `layout` for `keyboard` ("vid:pid" or name) until the daemon
+    /// exits
+    async fn set_layout_for_keyboard(&self, keyboard: String, layout: u32) -> fdo::Result<()> {
+        self.request(ipc::Request::SetLayout { keyboard, layout })
+            .await
+    }
+
+    /// Re-enumerate keyboards
+    async fn rescan(&self) -> fdo::Result<()> {
+        self.request(ipc::Request::Rescan).await
+    }
+}
+
+/// Serve `org.kunai.Daemon1` on the session bus, emitting PropertiesChanged
+/// whenever `snapshots` changes. `DBUS_SESSION_BUS_ADDRESS` selects the bus.
+pub async fn serve(
+    requests: mpsc::UnboundedSender<ipc::Envelope>,
+    snapshots: watch::Receiver<Snapshot>,
+) -> Result<()> {
+    serve_on(connection::Builder::session()?, requests, snapshots).await
+}
+
+async fn serve_on(
+    bus: connection::Builder<'_>,
+    requests: mpsc::UnboundedSender<ipc::Envelope>,
+    mut snapshots: watch::Receiver<Snapshot>,
+) -> Result<()> {
+    let daemon = Daemon {
+        requests,
+        snapshot: snapshots.borrow_and_update().clone(),
+    };
+    let name = bus_name();
+    let connection = bus
+        .name(name.as_str())?
+        .serve_at(PATH, daemon)?
+        .build()
+        .await?;
+    debug!("Serving {} on the session bus as {}", PATH, name);
+
+    let iface = connection
+        .object_server()
+        .interface::<_, Daemon>(PATH)
+        .await?;
+    while snapshots.changed().await.is_ok() {
+        let snapshot = snapshots.borrow_and_update().clone();
+        let mut daemon = iface.get_mut().await;
+        let previous = std::mem::replace(&mut daemon.snapshot, snapshot);
+        emit_changes(&daemon, &previous, iface.signal_emitter()).await?;
+    }
+
+    Ok(())
+}
+
+/// Emit PropertiesChanged for what differs between `previous` and now.
+async fn emit_changes(
+    daemon: &Daemon,
+    previous: &Snapshot,
+    emitter: &SignalEmitter<'_>,
+) -> zbus::Result<()> {
+    let current = &daemon.snapshot;
+    if current.active != previous.active {
+        daemon.active_keyboard_changed(emitter).await?;
+    }
+    if (current.layout_index, &current.layout) != (previous.layout_index, &previous.layout) {
+        daemon.current_layout_changed(emitter).await?;
+    }
+    if current.keyboards != previous.keyboards {
+        daemon.keyboards_changed(emitter).await?;
+    }
+    if current.paused != previous.paused {
+        daemon.paused_changed(emitter).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use zbus::{
+        proxy,
+        zvariant::OwnedValue,
+    };
+
+    use super::*;
+    use crate::{
+        ipc::KeyboardStatus,
+        test_bus::TestBus,
+    };
+
+    #[proxy(interface = "org.kunai.Daemon1", default_path = "/org/kunai/Daemon1")]
+    trait Daemon1 {
+        #[zbus(property)]
+        fn active_keyboard(&self) -> zbus::Result<(String, String)>;
+
+        #[zbus(property)]
+        fn current_layout(&self) -> zbus::Result<(i32, String)>;
+
+        #[zbus(property)]
+        fn keyboards(&self) -> zbus::Result<Vec<(String, String)>>;
+
+        #[zbus(property)]
+        fn paused(&self) -> zbus::Result<bool>;
+
+        fn pause(&self, timeout_secs: u64) -> zbus::Result<()>;
+
+        fn set_layout_for_keyboard(&self, keyboard: &str, layout: u32) -> zbus::Result<()>;
+    }
+
+    fn keyboard(device_id: &str, name: &str) -> KeyboardStatus {
+        KeyboardStatus {
+            device_id: device_id.to_string(),
+            name:      name.to_string(),
+        }
+    }
+
+    /// Answer requests like the daemon would, recording them
+    fn stub_daemon() -> (
+        mpsc::UnboundedSender<ipc::Envelope>,
+        mpsc::UnboundedReceiver<ipc::Request>,
+    ) {
+        let (request_tx, mut request_rx) = mpsc::unbounded_channel::<ipc::Envelope>();
+        let (seen_tx, seen_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some((request, reply)) = request_rx.recv().await {
+                let response = match &request {
+                    ipc::Request::SetLayout { keyboard, .. } => ipc::Response::Error {
+                        message: format!("Unknown keyboard {:?}", keyboard),
+                    },
+                    _ => ipc::Response::Ok,
+                };
+                let _ = seen_tx.send(request);
+                let _ = reply.send(response);
+            }
+        });
+        (request_tx, seen_rx)
+    }
+
+    async fn wait_for_name(connection: &zbus::Connection) {
+        let bus = fdo::DBusProxy::new(connection).await.unwrap();
+        let name = bus_name();
+        for _ in 0..500 {
+            if bus
+                .name_has_owner(name.as_str().try_into().unwrap())
+                .await
+                .unwrap()
+            {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("{} was not claimed within 5s", name);
+    }
+
+    #[tokio::test]
+    async fn serves_snapshot_and_requests() {
+        let Some(bus) = TestBus::start() else {
+            eprintln!("dbus-daemon is not installed, skipping");
+            return;
+        };
+        let (snapshot_tx, snapshot_rx) = watch::channel(Snapshot {
+            active:       Some(keyboard("aaaa:0001", "A")),
+            layout_index: Some(1),
+            layout:       Some("German".to_string()),
+            paused:       false,
+            keyboards:    vec![keyboard("aaaa:0001", "A"), keyboard("bbbb:0002", "B")],
+        });
+        let (requests, mut seen) = stub_daemon();
+        tokio::spawn(serve_on(bus.builder(), requests, snapshot_rx));
+
+        let client = bus.connect().await;
+        wait_for_name(&client).await;
+        let daemon = Daemon1Proxy::builder(&client)
+            .destination(bus_name())
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            daemon.active_keyboard().await.unwrap(),
+            ("aaaa:0001".to_string(), "A".to_string())
+        );
+        assert_eq!(
+            daemon.current_layout().await.unwrap(),
+            (1, "German".to_string())
+        );
+        assert_eq!(
+            daemon.keyboards().await.unwrap(),
+            vec![
+                ("aaaa:0001".to_string(), "A".to_string()),
+                ("bbbb:0002".to_string(), "B".to_string()),
+            ]
+        );
+        assert!(!daemon.paused().await.unwrap());
+
+        daemon.pause(30).await.unwrap();
+        assert!(matches!(
+            seen.recv().await,
+            Some(ipc::Request::Pause {
+                timeout_secs: Some(30),
+            })
+        ));
+        let error = daemon.set_layout_for_keyboard("C", 0).await.unwrap_err();
+        assert!(
+            error.to_string().contains("Unknown keyboard \"C\""),
+            "{}",
+            error
+        );
+
+        // Each changed property gets a PropertiesChanged signal
+        let properties = fdo::PropertiesProxy::builder(&client)
+            .destination(bus_name())
+            .unwrap()
+            .path(PATH)
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let mut changes = properties.receive_properties_changed().await.unwrap();
+        snapshot_tx.send_modify(|snapshot| {
+            snapshot.layout_index = None;
+            snapshot.layout = None;
+            snapshot.paused = true;
+        });
+
+        let mut changed = std::collections::HashMap::new();
+        while changed.len() < 2 {
+            let signal = tokio::time::timeout(Duration::from_secs(5), changes.next())
+                .await
+                .expect("no PropertiesChanged within 5s")
+                .unwrap();
+            let args = signal.args().unwrap();
+            assert_eq!(args.interface_name().as_str(), NAME);
+            for (name, value) in args.changed_properties() {
+                changed.insert(name.to_string(), OwnedValue::try_from(value).unwrap());
+            }
+        }
+        assert_eq!(
+            <(i32, String)>::try_from(changed.remove("CurrentLayout").unwrap()).unwrap(),
+            (-1, String::new())
+        );
+        assert!(bool::try_from(changed.remove("Paused").unwrap()).unwrap());
+        assert!(changed.is_empty(), "unexpected changes: {:?}", changed);
+    }
+}
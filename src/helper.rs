@@ -0,0 +1,394 @@
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+    },
+    os::unix::fs::PermissionsExt,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use anyhow::Result;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use tokio::{
+    io::{
+        AsyncBufReadExt,
+        AsyncWriteExt,
+        BufReader,
+    },
+    net::{
+        UnixListener,
+        UnixStream,
+    },
+    sync::{
+        broadcast,
+        mpsc,
+        watch,
+    },
+};
+use tracing::{
+    debug,
+    info,
+    warn,
+};
+
+use crate::{
+    input,
+    ipc::KeyboardStatus,
+    reactor::{
+        self,
+        Reactor,
+        Readiness,
+    },
+};
+
+/// Where the helper's system service listens
+pub const DEFAULT_SOCKET: &str = "/run/kunai/helper.sock";
+
+/// How often the helper tells clients that the active keyboard is still
+/// being typed on, at most
+const TYPING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Give a keyboard that stopped reading time to come back before rescanning
+const RESCAN_DELAY: Duration = Duration::from_millis(500);
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// What the helper tells the daemon. One JSON object per line; key codes
+/// never leave the helper.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Message {
+    /// Every keyboard the helper reads. Sent on connect and on every change.
+    Keyboards { keyboards: Vec<KeyboardStatus> },
+    /// Keys were pressed on `device_id`, and the previous press was on
+    /// another keyboard
+    Active { device_id: String },
+    /// The keyboard of the last `Active` is still being typed on. Sent at
+    /// most once a second.
+    Typing,
+}
+
+/// Resolve `--allow-user` values, given as names or numeric uids.
+fn resolve_users(users: &[String]) -> Result<HashSet<u32>> {
+    users
+        .iter()
+        .map(|user| {
+            if let Ok(uid) = user.parse() {
+                return Ok(uid);
+            }
+            match nix::unistd::User::from_name(user)? {
+                Some(user) => Ok(user.uid.as_raw()),
+                None => anyhow::bail!("Unknown user {:?}", user),
+            }
+        })
+        .collect()
+}
+
+fn bind(socket: &Path) -> Result<UnixListener> {
+    if let Some(parent) = socket.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let _ = std::fs::remove_file(socket);
+    let listener = UnixListener::bind(socket)
+        .map_err(|e| anyhow::anyhow!("Could not listen on {}: {}", socket.display(), e))?;
+    // Anyone may connect; clients are authorised by their peer credentials
+    std::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o666))?;
+    Ok(listener)
+}
+
+/// The keyboards the helper has open, by "vid:pid"
+struct Keyboards {
+    reactor:    Reactor<String>,
+    open:       HashMap<String, (u64, String)>, // "vid:pid" -> (reactor token, name)
+    next_token: u64,
+}
+
+impl Keyboards {
+    /// Open keyboards that appeared and close those that are gone. Returns
+    /// the keyboards now being read.
+    fn rescan(&mut self) -> Vec<KeyboardStatus> {
+        match input::list_keyboards() {
+            Ok(keyboards) => {
+                let mut present = HashSet::new();
+                for kb in keyboards {
+                    let device_id = format!("{:04x}:{:04x}", kb.vendor_id, kb.product_id);
+                    present.insert(device_id.clone());
+                    if self.open.contains_key(&device_id) {
+                        continue;
+                    }
+
+                    let token = self.next_token;
+                    let opened = reactor::open_device(&kb.device_path)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|device| self.reactor.add(token, device, device_id.clone()));
+                    match opened {
+                        Ok(()) => {
+                            info!("Reading {} ({})", kb.name, device_id);
+                            self.next_token += 1;
+                            self.open.insert(device_id, (token, kb.name));
+                        }
+                        Err(e) => warn!("Failed to open {} ({}): {}", kb.name, device_id, e),
+                    }
+                }
+
+                self.open.retain(|device_id, (token, name)| {
+                    if present.contains(device_id) {
+                        return true;
+                    }
+                    let _ = self.reactor.remove(*token);
+                    info!("Stopped reading {} ({})", name, device_id);
+                    false
+                });
+            }
+            // Keep what we have; the next device change tries again
+            Err(e) => warn!("Failed to enumerate keyboards: {}", e),
+        }
+
+        let mut keyboards: Vec<KeyboardStatus> = self
+            .open
+            .iter()
+            .map(|(device_id, (_, name))| KeyboardStatus {
+                device_id: device_id.clone(),
+                name:      name.clone(),
+            })
+            .collect();
+        keyboards.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+        keyboards
+    }
+}
+
+/// `kunai helper`: read every keyboard (as root) and tell the allowed users'
+/// daemons which one is being typed on.
+pub async fn run(socket: &Path, allow_users: &[String]) -> Result<()> {
+    let allowed = resolve_users(allow_users)?;
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .map_err(|e| anyhow::anyhow!("Failed to register SIGTERM handler: {}", e))?;
+
+    // Keyboards with key presses, throttled so that one keyboard being typed
+    // on is reported at most once per TYPING_INTERVAL
+    let (press_tx, _) = broadcast::channel::<String>(64);
+    let (closed_tx, mut closed_rx) = mpsc::unbounded_channel::<String>();
+    let reactor = {
+        let press_tx = press_tx.clone();
+        let mut last: Option<(String, Instant)> = None;
+        Reactor::spawn(move |_, device_id: &String, readiness| match readiness {
+            Readiness::KeyPresses(_) => {
+                let now = Instant::now();
+                let report = match &last {
+                    Some((last_id, at)) => {
+                        last_id != device_id || now.duration_since(*at) >= TYPING_INTERVAL
+                    }
+                    None => true,
+                };
+                if report {
+                    last = Some((device_id.clone(), now));
+                    let _ = press_tx.send(device_id.clone());
+                }
+            }
+            Readiness::Closed(e) => {
+                debug!("{} stream ended: {}", device_id, e);
+                let _ = closed_tx.send(device_id.clone());
+            }
+        })?
+    };
+
+    let mut keyboards = Keyboards {
+        reactor,
+        open: HashMap::new(),
+        next_token: 0,
+    };
+    let (keyboards_tx, _) = watch::channel(keyboards.rescan());
+
+    // Rescan when USB devices come and go
+    let (hotplug_tx, mut hotplug_rx) = mpsc::unbounded_channel::<()>();
+    if rusb::has_hotplug() {
+        let (sync_tx, sync_rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            if let Err(e) = input::run_hotplug_monitor(
+                Arc::new(RwLock::new(input::HotplugFilter::all())),
+                sync_tx,
+            ) {
+                warn!("Hotplug monitor failed: {}", e);
+            }
+        });
+        tokio::task::spawn_blocking(move || {
+            while let Ok(()) = sync_rx.recv() {
+                if hotplug_tx.send(()).is_err() {
+                    break;
+                }
+            }
+        });
+    } else {
+        warn!("USB hotplug not supported on this system");
+    }
+
+    let listener = bind(socket)?;
+    info!(
+        "Input helper listening on {} for uid(s) {:?}",
+        socket.display(),
+        allowed
+    );
+
+    // Rescan once keyboards that stopped reading or USB changes have settled
+    let mut rescan_at: Option<Instant> = None;
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let stream = match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        warn!("Helper socket accept failed: {}", e);
+                        continue;
+                    }
+                };
+                match stream.peer_cred() {
+                    Ok(cred) if cred.uid() == 0 || allowed.contains(&cred.uid()) => {
+                        debug!("Client connected (uid {}, pid {:?})", cred.uid(), cred.pid());
+                        let presses = press_tx.subscribe();
+                        let keyboards = keyboards_tx.subscribe();
+                        tokio::spawn(async move {
+                            if let Err(e) = serve(stream, presses, keyboards).await {
+                                debug!("Client (uid {}) went away: {}", cred.uid(), e);
+                            }
+                        });
+                    }
+                    Ok(cred) => warn!(
+                        "Refusing client with uid {} (pid {:?}), not allowed",
+                        cred.uid(),
+                        cred.pid()
+                    ),
+                    Err(e) => warn!("Could not read client credentials: {}", e),
+                }
+            }
+
+            // A keyboard stopped reading or a USB device came or went
+            Some(device_id) = closed_rx.recv() => {
+                keyboards.open.remove(&device_id);
+                rescan_at = Some(Instant::now() + RESCAN_DELAY);
+            }
+            Some(()) = hotplug_rx.recv() => {
+                rescan_at = Some(Instant::now() + RESCAN_DELAY);
+            }
+            _ = crate::sleep_until_deadline(rescan_at) => {
+                rescan_at = None;
+                keyboards_tx.send_replace(keyboards.rescan());
+            }
+
+            _ = sigterm.recv() => {
+                info!("Received SIGTERM, shutting down");
+                let _ = std::fs::remove_file(socket);
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Stream messages to one client until it goes away.
+async fn serve(
+    mut stream: UnixStream,
+    mut presses: broadcast::Receiver<String>,
+    mut keyboards: watch::Receiver<Vec<KeyboardStatus>>,
+) -> Result<()> {
+    keyboards.mark_changed();
+    let mut active: Option<String> = None;
+
+    loop {
+        let message = tokio::select! {
+            changed = keyboards.changed() => {
+                changed?;
+                Message::Keyboards {
+                    keyboards: keyboards.borrow_and_update().clone(),
+                }
+            }
+            press = presses.recv() => match press {
+                Ok(device_id) if active.as_ref() == Some(&device_id) => Message::Typing,
+                Ok(device_id) => {
+                    active = Some(device_id.clone());
+                    Message::Active { device_id }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+        };
+
+        let mut line = serde_json::to_string(&message)?;
+        line.push('\n');
+        stream.write_all(line.as_bytes()).await?;
+    }
+}
+
+/// Connect to the helper at `socket` and forward its messages, reconnecting
+/// whenever it goes away. Its keyboards count as disconnected meanwhile.
+pub async fn follow(socket: PathBuf, tx: mpsc::UnboundedSender<Message>) {
+    let mut delay = RECONNECT_DELAY;
+    let mut warned = false;
+
+    loop {
+        match UnixStream::connect(&socket).await {
+            Ok(stream) => {
+                info!("Connected to input helper at {}", socket.display());
+                delay = RECONNECT_DELAY;
+                warned = false;
+
+                let mut lines = BufReader::new(stream).lines();
+                loop {
+                    match lines.next_line().await {
+                        Ok(Some(line)) => match serde_json::from_str(&line) {
+                            Ok(message) => {
+                                if tx.send(message).is_err() {
+                                    return;
+                                }
+                            }
+                            Err(e) => warn!("Invalid message from input helper: {}", e),
+                        },
+                        Ok(None) => {
+                            warn!(
+                                "Input helper closed the connection (is this user allowed with \
+                                 --allow-user?)"
+                            );
+                            break;
+                        }
+                        Err(e) => {
+                            warn!("Lost the input helper: {}", e);
+                            break;
+                        }
+                    }
+                }
+
+                let gone = Message::Keyboards {
+                    keyboards: Vec::new(),
+                };
+                if tx.send(gone).is_err() {
+                    return;
+                }
+            }
+            Err(e) if !warned => {
+                warn!(
+                    "Cannot reach input helper at {}: {}, retrying",
+                    socket.display(),
+                    e
+                );
+                warned = true;
+            }
+            Err(e) => debug!("Input helper still unreachable: {}", e),
+        }
+
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+    }
+}
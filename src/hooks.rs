@@ -0,0 +1,236 @@
+use std::{
+    collections::VecDeque,
+    process::Stdio,
+    time::Duration,
+};
+
+use nix::{
+    sys::signal::{
+        Signal,
+        killpg,
+    },
+    unistd::Pid,
+};
+use tokio::{
+    process::Command,
+    sync::mpsc,
+    time::Instant,
+};
+use tracing::{
+    debug,
+    info,
+    warn,
+};
+
+use crate::{
+    config::{
+        HookConfig,
+        HookEvent,
+    },
+    niri,
+};
+
+impl HookEvent {
+    fn as_str(self) -> &'static str {
+        match self {
+            HookEvent::Switch => "switch",
+            HookEvent::Connect => "connect",
+            HookEvent::Disconnect => "disconnect",
+            HookEvent::Pause => "pause",
+            HookEvent::Resume => "resume",
+        }
+    }
+}
+
+/// Something that happened, as hooks get to see it
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub kind:        HookEvent,
+    /// Keyboard ("vid:pid") that caused it, if any
+    pub device_id:   Option<String>,
+    pub device_name: Option<String>,
+    pub layout:      Option<u32>,
+}
+
+impl Event {
+    pub fn new(kind: HookEvent) -> Self {
+        Self {
+            kind,
+            device_id: None,
+            device_name: None,
+            layout: None,
+        }
+    }
+
+    pub fn device(mut self, device_id: &str, name: Option<&str>) -> Self {
+        self.device_id = Some(device_id.to_string());
+        self.device_name = name.map(str::to_string);
+        self
+    }
+
+    pub fn layout(mut self, layout: u32) -> Self {
+        self.layout = Some(layout);
+        self
+    }
+}
+
+struct Hook {
+    config: HookConfig,
+    tx:     mpsc::UnboundedSender<Event>,
+}
+
+impl Hook {
+    fn matches(&self, event: &Event) -> bool {
+        self.config.event == event.kind
+            && self.config.keyboard.as_deref().is_none_or(|keyboard| {
+                event.device_id.as_deref() == Some(keyboard)
+                    || event.device_name.as_deref() == Some(keyboard)
+            })
+    }
+}
+
+/// The configured hooks. Each runs on its own task, so firing one never
+/// waits for it. Events that come in while a hook runs, or less than
+/// `min_interval_ms` after it started, queue up; several for the same
+/// keyboard collapse into the latest.
+pub struct Hooks {
+    hooks:   Vec<Hook>,
+    dry_run: bool,
+}
+
+impl Hooks {
+    /// Start a task per hook. Must be called within the tokio runtime.
+    pub fn spawn(configs: &[HookConfig], dry_run: bool) -> Self {
+        let hooks = configs
+            .iter()
+            .map(|config| {
+                let (tx, rx) = mpsc::unbounded_channel();
+                if !dry_run {
+                    tokio::spawn(run(config.clone(), rx));
+                }
+                Hook {
+                    config: config.clone(),
+                    tx,
+                }
+            })
+            .collect();
+        Self { hooks, dry_run }
+    }
+
+    pub fn fire(&self, event: Event) {
+        for hook in self.hooks.iter().filter(|hook| hook.matches(&event)) {
+            if self.dry_run {
+                info!(
+                    "[DRY-RUN] Would run {} hook: {}",
+                    event.kind.as_str(),
+                    hook.config.command
+                );
+                continue;
+            }
+            let _ = hook.tx.send(event.clone());
+        }
+    }
+}
+
+async fn run(config: HookConfig, mut rx: mpsc::UnboundedReceiver<Event>) {
+    let timeout = Duration::from_millis(config.timeout_ms);
+    let interval = Duration::from_millis(config.min_interval_ms);
+    let mut pending: VecDeque<Event> = VecDeque::new();
+
+    loop {
+        if pending.is_empty() {
+            match rx.recv().await {
+                Some(event) => pending.push_back(event),
+                None => return,
+            }
+        }
+        while let Ok(event) = rx.try_recv() {
+            match pending.iter_mut().find(|e| e.device_id == event.device_id) {
+                Some(queued) => *queued = event,
+                None => pending.push_back(event),
+            }
+        }
+
+        let Some(event) = pending.pop_front() else {
+            continue;
+        };
+        let started = Instant::now();
+        run_once(&config.command, &event, timeout).await;
+        tokio::time::sleep_until(started + interval).await;
+    }
+}
+
+async fn run_once(command: &str, event: &Event, timeout: Duration) {
+    // Looked up here rather than in the daemon loop, since it asks niri
+    let layout_name = match event.layout {
+        Some(index) => tokio::task::spawn_blocking(niri::get_layouts)
+            .await
+            .ok()
+            .and_then(Result::ok)
+            .and_then(|names| names.get(index as usize).cloned()),
+        None => None,
+    };
+
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("KUNAI_EVENT", event.kind.as_str())
+        .env("KUNAI_DEVICE", event.device_id.as_deref().unwrap_or(""))
+        .env(
+            "KUNAI_DEVICE_NAME",
+            event.device_name.as_deref().unwrap_or(""),
+        )
+        .env(
+            "KUNAI_LAYOUT_INDEX",
+            event.layout.map(|l| l.to_string()).unwrap_or_default(),
+        )
+        .env("KUNAI_LAYOUT_NAME", layout_name.unwrap_or_default())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        // Own process group, so a timeout also kills what the command started
+        .process_group(0)
+        .kill_on_drop(true)
+        .spawn();
+    let child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            warn!(
+                "Could not run {} hook `{}`: {}",
+                event.kind.as_str(),
+                command,
+                e
+            );
+            return;
+        }
+    };
+    let pgid = child.id().map(|pid| Pid::from_raw(pid as i32));
+
+    debug!("Running {} hook: {}", event.kind.as_str(), command);
+    match tokio::time::timeout(timeout, child.wait_with_output()).await {
+        Ok(Ok(output)) if output.status.success() => {}
+        Ok(Ok(output)) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            warn!(
+                "{} hook `{}` failed ({}){}{}",
+                event.kind.as_str(),
+                command,
+                output.status,
+                if stderr.trim().is_empty() { "" } else { ": " },
+                stderr.trim()
+            );
+        }
+        Ok(Err(e)) => warn!("{} hook `{}` failed: {}", event.kind.as_str(), command, e),
+        Err(_) => {
+            if let Some(pgid) = pgid {
+                let _ = killpg(pgid, Signal::SIGKILL);
+            }
+            warn!(
+                "{} hook `{}` timed out after {:?} and was killed",
+                event.kind.as_str(),
+                command,
+                timeout
+            );
+        }
+    }
+}
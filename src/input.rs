@@ -8,7 +8,10 @@ use std::{
         Path,
         PathBuf,
     },
-    sync::Arc,
+    sync::{
+        Arc,
+        RwLock,
+    },
 };
 
 use anyhow::Result;
@@ -18,15 +21,20 @@ use evdev::{
     KeyCode,
 };
 use rusb::{
+    Context,
     Hotplug,
+    HotplugBuilder,
     UsbContext,
 };
 use tracing::{
     debug,
+    error,
     info,
     warn,
 };
 
+use crate::config::Config;
+
 pub struct Keyboard {
     pub name:        String,
     pub device_path: PathBuf,
@@ -168,9 +176,81 @@ pub fn list_keyboards() -> Result<Vec<Keyboard>> {
     Ok(keyboards.into_values().collect())
 }
 
-pub struct HotPlugHandler {
-    pub configured_devices: Arc<HashSet<(u16, u16)>>,
-    pub signal_tx:          std::sync::mpsc::Sender<()>,
+/// Which USB devices the hotplug monitor reports
+#[derive(Debug, Default)]
+pub struct HotplugFilter {
+    /// Configured keyboards as USB (vendor, product) IDs
+    pub configured:           HashSet<(u16, u16)>,
+    /// Also report devices not in config (when a default layout is set)
+    pub include_unconfigured: bool,
+}
+
+impl HotplugFilter {
+    pub fn new(config: &Config) -> Self {
+        let configured = config
+            .keyboards
+            .iter()
+            .filter_map(|kb| {
+                let vid = u16::from_str_radix(&kb.vendor_id, 16).ok()?;
+                let pid = u16::from_str_radix(&kb.product_id, 16).ok()?;
+                Some((vid, pid))
+            })
+            .collect();
+        Self {
+            configured,
+            include_unconfigured: config.default_layout.is_some(),
+        }
+    }
+
+    /// Report every USB device, for the input helper
+    pub fn all() -> Self {
+        Self {
+            configured:           HashSet::new(),
+            include_unconfigured: true,
+        }
+    }
+}
+
+/// Signal `signal_tx` whenever a USB device `filter` allows comes or goes.
+/// Blocks, so run it on its own thread.
+pub fn run_hotplug_monitor(
+    filter: Arc<RwLock<HotplugFilter>>,
+    signal_tx: std::sync::mpsc::Sender<()>,
+) -> Result<()> {
+    let context = Context::new()?;
+
+    let _reg: rusb::Registration<Context> = HotplugBuilder::new()
+        .enumerate(false) // Don't enumerate on registration
+        .register(&context, Box::new(HotPlugHandler { filter, signal_tx }))?;
+
+    info!("USB hotplug monitoring started");
+
+    loop {
+        if let Err(e) = context.handle_events(None) {
+            error!("USB context error: {}", e);
+            return Err(e.into());
+        }
+    }
+}
+
+struct HotPlugHandler {
+    /// Updated when the daemon reloads its config
+    filter:    Arc<RwLock<HotplugFilter>>,
+    signal_tx: std::sync::mpsc::Sender<()>,
+}
+
+impl HotPlugHandler {
+    fn is_configured(&self, vid: u16, pid: u16) -> bool {
+        self.filter
+            .read()
+            .is_ok_and(|filter| filter.configured.contains(&(vid, pid)))
+    }
+
+    fn include_unconfigured(&self) -> bool {
+        self.filter
+            .read()
+            .is_ok_and(|filter| filter.include_unconfigured)
+    }
 }
 
 impl<T: UsbContext> Hotplug<T> for HotPlugHandler {
@@ -184,9 +264,12 @@ impl<T: UsbContext> Hotplug<T> for HotPlugHandler {
         let pid = device_desc.product_id();
 
         // Only signal if this device is in config
-        if self.configured_devices.contains(&(vid, pid)) {
+        if self.is_configured(vid, pid) {
             info!("Configured keyboard detected: {:04x}:{:04x}", vid, pid);
             let _ = self.signal_tx.send(());
+        } else if self.include_unconfigured() {
+            debug!("USB device detected: {:04x}:{:04x}", vid, pid);
+            let _ = self.signal_tx.send(());
         } else {
             debug!("Ignoring non-configured device: {:04x}:{:04x}", vid, pid);
         }
@@ -202,9 +285,12 @@ impl<T: UsbContext> Hotplug<T> for HotPlugHandler {
         let pid = device_desc.product_id();
 
         // Only signal if this device is in config
-        if self.configured_devices.contains(&(vid, pid)) {
+        if self.is_configured(vid, pid) {
             info!("Configured keyboard disconnected: {:04x}:{:04x}", vid, pid);
             let _ = self.signal_tx.send(());
+        } else if self.include_unconfigured() {
+            debug!("USB device disconnected: {:04x}:{:04x}", vid, pid);
+            let _ = self.signal_tx.send(());
         } else {
             debug!(
                 "Ignoring non-configured device removal: {:04x}:{:04x}",
@@ -0,0 +1,171 @@
+use std::{
+    fs,
+    io::Write,
+    path::PathBuf,
+    sync::OnceLock,
+};
+
+use anyhow::Result;
+use nix::{
+    errno::Errno,
+    fcntl::{
+        Flock,
+        FlockArg,
+    },
+    unistd::Pid,
+};
+use tracing::{
+    debug,
+    warn,
+};
+
+pub const DEFAULT_INSTANCE: &str = "default";
+
+static INSTANCE_NAME: OnceLock<String> = OnceLock::new();
+
+/// Select the daemon instance all runtime paths refer to. Must be called once
+/// at startup, before any path is resolved.
+pub fn set_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        anyhow::bail!(
+            "Invalid instance name {:?}: use letters, digits, '-' or '_'",
+            name
+        );
+    }
+    let _ = INSTANCE_NAME.set(name.to_string());
+    Ok(())
+}
+
+pub fn name() -> &'static str {
+    INSTANCE_NAME
+        .get()
+        .map(String::as_str)
+        .unwrap_or(DEFAULT_INSTANCE)
+}
+
+/// Directory for per-session runtime files (lock file, control socket).
+///
+/// Uses `$XDG_RUNTIME_DIR/kunai`, which is cleared on logout/reboot so stale
+/// PIDs never survive. Falls back to the config directory if it is unset.
+pub fn runtime_dir() -> Result<PathBuf> {
+    let dir = match dirs::runtime_dir() {
+        Some(dir) => dir.join("kunai"),
+        None => dirs::config_dir()
+            .ok_or_else(|| anyhow::anyhow!("Could not find config directory"))?
+            .join("kunai"),
+    };
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Path of a runtime file for the current instance, e.g. `default.pid`.
+pub fn runtime_path(extension: &str) -> Result<PathBuf> {
+    Ok(runtime_dir()?.join(format!("{}.{}", name(), extension)))
+}
+
+pub fn pid_file_path() -> Result<PathBuf> {
+    runtime_path("pid")
+}
+
+/// Exclusive lock on the PID file, held for the lifetime of the daemon.
+///
+/// The kernel releases the lock when the process exits, so a crashed daemon
+/// never blocks the next one, and two daemons racing to start cannot both
+/// acquire it.
+pub struct InstanceLock {
+    _file: Flock<fs::File>,
+}
+
+impl InstanceLock {
+    pub fn acquire() -> Result<Self> {
+        let path = pid_file_path()?;
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&path)?;
+
+        let mut file = match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+            Ok(file) => file,
+            Err((_, Errno::EWOULDBLOCK)) => {
+                let holder = read_pid_file()?
+                    .map(|pid| format!(" (PID {})", pid))
+                    .unwrap_or_default();
+                anyhow::bail!(
+                    "Daemon instance '{}' already running{}. Use --restart to replace it.",
+                    name(),
+                    holder
+                );
+            }
+            Err((_, e)) => anyhow::bail!("Failed to lock {}: {}", path.display(), e),
+        };
+
+        file.set_len(0)?;
+        write!(*file, "{}", std::process::id())?;
+        file.sync_all()?;
+        debug!("Acquired instance lock: {}", path.display());
+
+        Ok(Self { _file: file })
+    }
+}
+
+fn read_pid_file() -> Result<Option<Pid>> {
+    let path = pid_file_path()?;
+    match fs::read_to_string(&path) {
+        Ok(contents) if contents.trim().is_empty() => Ok(None),
+        Ok(contents) => match contents.trim().parse::<i32>() {
+            Ok(pid) => Ok(Some(Pid::from_raw(pid))),
+            Err(_) => {
+                warn!("Invalid PID in {}: {:?}", path.display(), contents);
+                Ok(None)
+            }
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns the PID of the running daemon for this instance, if any.
+///
+/// A daemon only counts as running while it holds the instance lock and its
+/// PID still belongs to a kunai process.
+pub fn running_pid() -> Result<Option<Pid>> {
+    let path = pid_file_path()?;
+    let file = match fs::File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    // If we can take the lock ourselves, nobody holds it
+    if Flock::lock(file, FlockArg::LockSharedNonblock).is_ok() {
+        return Ok(None);
+    }
+
+    Ok(read_pid_file()?.filter(|pid| is_process_alive(*pid)))
+}
+
+/// Check that `pid` exists and is a kunai process, so a PID reused by an
+/// unrelated program is never mistaken for the daemon.
+pub fn is_process_alive(pid: Pid) -> bool {
+    // Signal 0 doesn't send a signal but checks if the process exists
+    if nix::sys::signal::kill(pid, None).is_err() {
+        return false;
+    }
+
+    let comm = fs::read_to_string(format!("/proc/{}/comm", pid)).unwrap_or_default();
+    if comm.trim() == "kunai" {
+        return true;
+    }
+
+    // comm can be changed at runtime; fall back to the executable name
+    fs::read_link(format!("/proc/{}/exe", pid))
+        .ok()
+        .and_then(|exe| exe.file_name().map(|n| n.to_string_lossy().into_owned()))
+        .is_some_and(|exe| exe.starts_with("kunai"))
+}
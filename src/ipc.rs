@@ -0,0 +1,276 @@
+use std::{
+    io::{
+        BufRead,
+        BufReader,
+        Write,
+    },
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::Result;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use tokio::{
+    io::{
+        AsyncBufReadExt,
+        AsyncWriteExt,
+    },
+    net::UnixListener,
+    sync::{
+        mpsc,
+        oneshot,
+        watch,
+    },
+};
+use tracing::{
+    debug,
+    warn,
+};
+
+/// A command sent from the CLI to the running daemon. One JSON object per line.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    Pause {
+        timeout_secs: Option<u64>,
+    },
+    Resume,
+    Cycle {
+        reverse: bool,
+    },
+    NextLanguage,
+    SetLanguage {
+        language: String,
+    },
+    /// Re-read config.toml
+    Reload,
+    /// Use `layout` for a keyboard, by "vid:pid" or name, until the daemon
+    /// exits
+    SetLayout {
+        keyboard: String,
+        layout:   u32,
+    },
+    /// Re-enumerate keyboards
+    Rescan,
+    /// Send a `Snapshot` now and whenever it changes, until the client goes
+    /// away. Answered by the socket server, not the main loop.
+    Watch,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "reply", rename_all = "snake_case")]
+pub enum Response {
+    Status(StatusInfo),
+    Ok,
+    Error { message: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatusInfo {
+    pub pid:         u32,
+    pub uptime_secs: u64,
+    pub dry_run:     bool,
+    pub paused:      bool,
+
+    pub resume_in_secs:     Option<u64>,
+    pub keyboards:          Vec<KeyboardStatus>,
+    /// Keyboards that failed to open or lost their input, being retried
+    #[serde(default)]
+    pub reopening:          Vec<KeyboardStatus>,
+    pub last_switch:        Option<SwitchInfo>,
+    pub language:           Option<String>,
+    /// No keyboards are configured yet, so nothing else is going on
+    #[serde(default)]
+    pub waiting_for_config: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyboardStatus {
+    pub device_id: String,
+    pub name:      String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SwitchInfo {
+    pub device_id:    String,
+    pub name:         String,
+    pub layout_index: u32,
+    pub at:           String,
+}
+
+/// What a status bar shows: streamed by `Watch` and kept in the snapshot
+/// file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Keyboard last typed on
+    pub active:       Option<KeyboardStatus>,
+    pub layout_index: Option<u32>,
+    /// niri's name for the layout, e.g. "English (US)"
+    pub layout:       Option<String>,
+    pub paused:       bool,
+    /// Connected keyboards being monitored
+    pub keyboards:    Vec<KeyboardStatus>,
+}
+
+/// A request received by the daemon, paired with the channel to answer on.
+pub type Envelope = (Request, oneshot::Sender<Response>);
+
+pub fn socket_path() -> Result<PathBuf> {
+    crate::instance::runtime_path("sock")
+}
+
+/// Where the daemon keeps its latest `Snapshot`, for bars that poll.
+pub fn snapshot_path() -> Result<PathBuf> {
+    crate::instance::runtime_path("state.json")
+}
+
+/// Replace the snapshot file, so readers never see it half-written.
+pub fn write_snapshot(snapshot: &Snapshot) -> Result<()> {
+    let path = snapshot_path()?;
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_string(snapshot)? + "\n")?;
+    std::fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+pub fn remove_snapshot() {
+    if let Ok(path) = snapshot_path()
+        && let Err(e) = std::fs::remove_file(&path)
+        && e.kind() != std::io::ErrorKind::NotFound
+    {
+        warn!("Failed to remove snapshot file: {}", e);
+    }
+}
+
+/// Send a single request to the daemon and wait for its response.
+pub fn send_request(request: &Request) -> Result<Response> {
+    let path = socket_path()?;
+    let mut stream = UnixStream::connect(&path)
+        .map_err(|e| anyhow::anyhow!("Could not connect to daemon at {}: {}", path.display(), e))?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    Ok(serde_json::from_str(&reply)?)
+}
+
+/// Subscribe to the daemon's snapshots and pass each one to `on_snapshot`
+/// until the daemon goes away.
+pub fn watch(mut on_snapshot: impl FnMut(&Snapshot) -> Result<()>) -> Result<()> {
+    let path = socket_path()?;
+    let mut stream = UnixStream::connect(&path)
+        .map_err(|e| anyhow::anyhow!("Could not connect to daemon at {}: {}", path.display(), e))?;
+
+    let mut line = serde_json::to_string(&Request::Watch)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+
+    for line in BufReader::new(stream).lines() {
+        on_snapshot(&serde_json::from_str(&line?)?)?;
+    }
+    Ok(())
+}
+
+/// Bind the control socket and forward every request to the main loop,
+/// except `Watch`, which is served from `snapshots`.
+///
+/// Any stale socket left behind by a crashed daemon is removed first; the
+/// caller has already made sure no other daemon is running.
+pub fn spawn_server(
+    request_tx: mpsc::UnboundedSender<Envelope>,
+    snapshots: watch::Receiver<Snapshot>,
+) -> Result<()> {
+    let path = socket_path()?;
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    debug!("Control socket listening on {}", path.display());
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("Control socket accept failed: {}", e);
+                    continue;
+                }
+            };
+
+            let request_tx = request_tx.clone();
+            let snapshots = snapshots.clone();
+            tokio::spawn(async move {
+                let (read_half, mut write_half) = stream.into_split();
+                let mut line = String::new();
+                if tokio::io::BufReader::new(read_half)
+                    .read_line(&mut line)
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+
+                let response = match serde_json::from_str::<Request>(&line) {
+                    Ok(Request::Watch) => {
+                        stream_snapshots(&mut write_half, snapshots).await;
+                        return;
+                    }
+                    Ok(request) => {
+                        let (reply_tx, reply_rx) = oneshot::channel();
+                        if request_tx.send((request, reply_tx)).is_err() {
+                            return;
+                        }
+                        match reply_rx.await {
+                            Ok(response) => response,
+                            Err(_) => return,
+                        }
+                    }
+                    Err(e) => Response::Error {
+                        message: format!("Invalid request: {}", e),
+                    },
+                };
+
+                if let Ok(mut reply) = serde_json::to_string(&response) {
+                    reply.push('\n');
+                    let _ = write_half.write_all(reply.as_bytes()).await;
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+/// Write the current snapshot, then each change, until the client or the
+/// daemon goes away.
+async fn stream_snapshots(
+    writer: &mut (impl tokio::io::AsyncWrite + Unpin),
+    mut snapshots: watch::Receiver<Snapshot>,
+) {
+    snapshots.mark_changed();
+    while snapshots.changed().await.is_ok() {
+        let Ok(mut line) = serde_json::to_string(&*snapshots.borrow_and_update()) else {
+            return;
+        };
+        line.push('\n');
+        if writer.write_all(line.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+pub fn remove_socket() {
+    if let Ok(path) = socket_path()
+        && let Err(e) = std::fs::remove_file(&path)
+        && e.kind() != std::io::ErrorKind::NotFound
+    {
+        warn!("Failed to remove control socket: {}", e);
+    }
+}
@@ -0,0 +1,358 @@
+use std::os::fd::OwnedFd;
+
+use anyhow::Result;
+use futures::{
+    Stream,
+    StreamExt,
+};
+use tokio::sync::mpsc;
+use tracing::{
+    debug,
+    warn,
+};
+use zbus::{
+    Connection,
+    proxy,
+    zvariant::OwnedObjectPath,
+};
+
+/// A logind delay inhibitor: the system waits for it to be dropped (or for
+/// logind's timeout) before going to sleep.
+#[derive(Debug)]
+pub struct Inhibitor {
+    _fd: OwnedFd,
+}
+
+/// Events from systemd-logind that the daemon cares about
+#[derive(Debug)]
+pub enum Event {
+    /// The system is about to sleep (`true`) or has just resumed (`false`).
+    /// Before sleep this carries our inhibitor, to drop once the keyboards
+    /// are closed.
+    PrepareForSleep(bool, Option<Inhibitor>),
+    /// Our session became the foreground one (`true`) or stopped being it,
+    /// e.g. after a VT or user switch. Also sent once with the initial state.
+    SessionActive(bool),
+}
+
+#[proxy(
+    interface = "org.freedesktop.login1.Manager",
+    default_service = "org.freedesktop.login1",
+    default_path = "/org/freedesktop/login1"
+)]
+trait Manager {
+    fn get_session(&self, session_id: &str) -> zbus::Result<OwnedObjectPath>;
+
+    #[zbus(name = "GetSessionByPID")]
+    fn get_session_by_pid(&self, pid: u32) -> zbus::Result<OwnedObjectPath>;
+
+    fn inhibit(
+        &self,
+        what: &str,
+        who: &str,
+        why: &str,
+        mode: &str,
+    ) -> zbus::Result<zbus::zvariant::OwnedFd>;
+
+    #[zbus(signal)]
+    fn prepare_for_sleep(&self, start: bool) -> zbus::Result<()>;
+}
+
+#[proxy(
+    interface = "org.freedesktop.login1.User",
+    default_service = "org.freedesktop.login1",
+    default_path = "/org/freedesktop/login1/user/self"
+)]
+trait User {
+    /// The user's graphical session, as (id, object path)
+    #[zbus(property)]
+    fn display(&self) -> zbus::Result<(String, OwnedObjectPath)>;
+}
+
+#[proxy(
+    interface = "org.freedesktop.login1.Session",
+    default_service = "org.freedesktop.login1"
+)]
+trait Session {
+    #[zbus(property)]
+    fn active(&self) -> zbus::Result<bool>;
+}
+
+/// Find the session we belong to: the one in `XDG_SESSION_ID`, else the one
+/// containing our process, else the user's graphical session (for systemd
+/// user services, which run outside any session).
+async fn find_session(
+    connection: &Connection,
+    manager: &ManagerProxy<'_>,
+) -> Result<OwnedObjectPath> {
+    if let Ok(id) = std::env::var("XDG_SESSION_ID")
+        && let Ok(path) = manager.get_session(&id).await
+    {
+        return Ok(path);
+    }
+    if let Ok(path) = manager.get_session_by_pid(std::process::id()).await {
+        return Ok(path);
+    }
+
+    let (id, path) = UserProxy::new(connection).await?.display().await?;
+    if id.is_empty() {
+        anyhow::bail!("no graphical session for this user");
+    }
+    Ok(path)
+}
+
+/// Delay sleep until we have closed the keyboards.
+async fn inhibit_sleep(manager: &ManagerProxy<'_>) -> Option<Inhibitor> {
+    match manager
+        .inhibit("sleep", "kunai", "Close keyboards before sleep", "delay")
+        .await
+    {
+        Ok(fd) => Some(Inhibitor { _fd: fd.into() }),
+        Err(e) => {
+            warn!(
+                "Could not delay sleep, keyboards may not be closed in time: {}",
+                e
+            );
+            None
+        }
+    }
+}
+
+/// The next item of a stream that may not exist; pending forever if not.
+async fn next_of<S: Stream + Unpin>(stream: &mut Option<S>) -> Option<S::Item> {
+    match stream {
+        Some(stream) => stream.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Follow logind on the system bus and forward relevant events until the
+/// connection ends. `DBUS_SYSTEM_BUS_ADDRESS` points this at another bus,
+/// e.g. one running a mock logind.
+pub async fn watch_events(tx: mpsc::UnboundedSender<Event>) -> Result<()> {
+    follow(&Connection::system().await?, tx).await
+}
+
+async fn follow(connection: &Connection, tx: mpsc::UnboundedSender<Event>) -> Result<()> {
+    let manager = ManagerProxy::new(connection).await?;
+    let mut sleep = manager.receive_prepare_for_sleep().await?;
+    let mut inhibitor = inhibit_sleep(&manager).await;
+    debug!("Listening for logind sleep signals");
+
+    let session = match find_session(connection, &manager).await {
+        Ok(path) => {
+            debug!("Following logind session {}", path.as_str());
+            Some(
+                SessionProxy::builder(connection)
+                    .path(path)?
+                    .build()
+                    .await?,
+            )
+        }
+        Err(e) => {
+            warn!(
+                "Could not find our logind session, assuming it stays active: {}",
+                e
+            );
+            None
+        }
+    };
+    let mut active_changes = match &session {
+        // Yields the current value first, then every change
+        Some(session) => Some(session.receive_active_changed().await),
+        None => None,
+    };
+
+    loop {
+        let event = tokio::select! {
+            Some(signal) = sleep.next() => match signal.args() {
+                Ok(args) if args.start => Event::PrepareForSleep(true, inhibitor.take()),
+                Ok(_) => {
+                    // Be ready for the next sleep before handling this one
+                    if inhibitor.is_none() {
+                        inhibitor = inhibit_sleep(&manager).await;
+                    }
+                    Event::PrepareForSleep(false, None)
+                }
+                Err(e) => {
+                    warn!("Ignoring malformed PrepareForSleep signal: {}", e);
+                    continue;
+                }
+            },
+            Some(change) = next_of(&mut active_changes) => match change.get().await {
+                Ok(active) => Event::SessionActive(active),
+                Err(e) => {
+                    warn!("Ignoring malformed session Active change: {}", e);
+                    continue;
+                }
+            },
+            else => break,
+        };
+        if tx.send(event).is_err() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            Arc,
+            atomic::{
+                AtomicUsize,
+                Ordering,
+            },
+        },
+        time::Duration,
+    };
+
+    use zbus::{
+        interface,
+        object_server::SignalEmitter,
+    };
+
+    use super::*;
+    use crate::test_bus::TestBus;
+
+    const MANAGER: &str = "/org/freedesktop/login1";
+    const SESSION: &str = "/org/freedesktop/login1/session/_31";
+
+    struct StubManager {
+        inhibitors: Arc<AtomicUsize>,
+    }
+
+    #[interface(name = "org.freedesktop.login1.Manager")]
+    impl StubManager {
+        fn get_session(&self, _session_id: &str) -> OwnedObjectPath {
+            OwnedObjectPath::try_from(SESSION).unwrap()
+        }
+
+        #[zbus(name = "GetSessionByPID")]
+        fn get_session_by_pid(&self, _pid: u32) -> OwnedObjectPath {
+            OwnedObjectPath::try_from(SESSION).unwrap()
+        }
+
+        fn inhibit(
+            &self,
+            what: &str,
+            _who: &str,
+            _why: &str,
+            mode: &str,
+        ) -> zbus::zvariant::OwnedFd {
+            assert_eq!((what, mode), ("sleep", "delay"));
+            self.inhibitors.fetch_add(1, Ordering::SeqCst);
+            OwnedFd::from(std::fs::File::open("/dev/null").unwrap()).into()
+        }
+
+        #[zbus(signal)]
+        async fn prepare_for_sleep(emitter: &SignalEmitter<'_>, start: bool) -> zbus::Result<()>;
+    }
+
+    struct StubSession {
+        active: bool,
+    }
+
+    #[interface(name = "org.freedesktop.login1.Session")]
+    impl StubSession {
+        #[zbus(property)]
+        fn active(&self) -> bool {
+            self.active
+        }
+    }
+
+    async fn next_event(rx: &mut mpsc::UnboundedReceiver<Event>) -> Event {
+        tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .expect("no logind event within 5s")
+            .expect("watcher stopped")
+    }
+
+    #[tokio::test]
+    async fn follows_sleep_and_session_changes() {
+        let Some(bus) = TestBus::start() else {
+            eprintln!("dbus-daemon is not installed, skipping");
+            return;
+        };
+        let inhibitors = Arc::new(AtomicUsize::new(0));
+        let logind = bus
+            .builder()
+            .name("org.freedesktop.login1")
+            .unwrap()
+            .serve_at(
+                MANAGER,
+                StubManager {
+                    inhibitors: inhibitors.clone(),
+                },
+            )
+            .unwrap()
+            .serve_at(SESSION, StubSession { active: true })
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+
+        let client = bus.connect().await;
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move { follow(&client, tx).await });
+
+        // The initial state, once the watcher is subscribed to everything
+        assert!(matches!(
+            next_event(&mut rx).await,
+            Event::SessionActive(true)
+        ));
+        assert_eq!(inhibitors.load(Ordering::SeqCst), 1);
+
+        let manager = logind
+            .object_server()
+            .interface::<_, StubManager>(MANAGER)
+            .await
+            .unwrap();
+        StubManager::prepare_for_sleep(manager.signal_emitter(), true)
+            .await
+            .unwrap();
+        assert!(matches!(
+            next_event(&mut rx).await,
+            Event::PrepareForSleep(true, Some(_))
+        ));
+
+        // A signal with the wrong arguments is skipped, not fatal
+        logind
+            .emit_signal(
+                None::<()>,
+                MANAGER,
+                "org.freedesktop.login1.Manager",
+                "PrepareForSleep",
+                &("not a bool",),
+            )
+            .await
+            .unwrap();
+        StubManager::prepare_for_sleep(manager.signal_emitter(), false)
+            .await
+            .unwrap();
+        assert!(matches!(
+            next_event(&mut rx).await,
+            Event::PrepareForSleep(false, None)
+        ));
+        assert_eq!(inhibitors.load(Ordering::SeqCst), 2);
+
+        let session = logind
+            .object_server()
+            .interface::<_, StubSession>(SESSION)
+            .await
+            .unwrap();
+        session.get_mut().await.active = false;
+        session
+            .get()
+            .await
+            .active_changed(session.signal_emitter())
+            .await
+            .unwrap();
+        assert!(matches!(
+            next_event(&mut rx).await,
+            Event::SessionActive(false)
+        ));
+    }
+}
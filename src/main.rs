@@ -1,15 +1,35 @@
+mod activity;
 mod config;
+mod dbus;
+mod helper;
+mod hooks;
 mod input;
+mod instance;
+mod ipc;
+mod logind;
 mod niri;
+mod policy;
+mod reactor;
+mod sandbox;
+mod script;
+mod switcher;
+mod systemd;
+#[cfg(test)]
+mod test_bus;
 mod ui;
+mod watch;
 
 use std::{
     collections::{
         HashMap,
         HashSet,
+        VecDeque,
     },
     path::PathBuf,
-    sync::Arc,
+    sync::{
+        Arc,
+        RwLock,
+    },
     time::{
         Duration,
         Instant,
@@ -21,17 +41,16 @@ use clap::{
     Parser,
     Subcommand,
 };
-use config::Config;
-use evdev::Device;
-use rusb::{
-    Context,
-    HotplugBuilder,
-    UsbContext,
-};
-use tokio::{
-    sync::mpsc,
-    task::JoinHandle,
+use config::{
+    AppRule,
+    Config,
+    HookEvent,
+    KeyboardConfig,
+    RuleMode,
+    StickyMode,
+    WorkspaceRule,
 };
+use tokio::sync::mpsc;
 use tracing::{
     Level,
     debug,
@@ -42,12 +61,21 @@ use tracing::{
     warn,
 };
 
-use crate::input::HotPlugHandler;
+use crate::activity::DeviceIndex;
 
 #[derive(Parser)]
 #[command(name = "kunai")]
 #[command(version, about = "Per-keyboard layout switcher for Niri", long_about = None)]
 struct Cli {
+    /// Daemon instance to run or control (allows several side by side)
+    #[arg(
+        long,
+        global = true,
+        env = "KUNAI_INSTANCE",
+        default_value = instance::DEFAULT_INSTANCE
+    )]
+    instance: String,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -80,16 +108,1121 @@ enum Commands {
 
     /// Live dashboard: monitor daemon log
     Dashboard,
+
+    /// Show whether the daemon is running and what it is doing
+    Status,
+
+    /// Stop the running daemon
+    Stop,
+
+    /// Print the active keyboard, layout and paused state on every change,
+    /// for status bars
+    Watch {
+        #[arg(long, value_enum, default_value_t = watch::Format::Text)]
+        format: watch::Format,
+    },
+
+    /// Temporarily stop switching layouts (e.g. while gaming)
+    Pause {
+        /// Automatically resume after this many seconds
+        #[arg(long, value_name = "SECS")]
+        timeout: Option<u64>,
+    },
+
+    /// Resume layout switching after a pause
+    Resume,
+
+    /// Switch to the next layout allowed for the active keyboard
+    Cycle {
+        /// Go to the previous layout instead
+        #[arg(long)]
+        reverse: bool,
+    },
+
+    /// Change the global language; each keyboard uses its layout for it
+    Language {
+        #[command(subcommand)]
+        action: LanguageAction,
+    },
+
+    /// Manage the systemd user service
+    Service {
+        #[command(subcommand)]
+        action: ServiceAction,
+    },
+
+    /// Work with the rules script
+    Rules {
+        #[command(subcommand)]
+        action: RulesAction,
+    },
+
+    /// Check the daemon's sandbox
+    Sandbox {
+        #[command(subcommand)]
+        action: SandboxAction,
+    },
+
+    /// Read keyboards as root and tell users' daemons which one is active
+    Helper {
+        /// User allowed to connect, by name or uid (repeatable); root always is
+        #[arg(long = "allow-user", value_name = "USER", required = true)]
+        allow_users: Vec<String>,
+
+        /// Socket to listen on
+        #[arg(long, default_value = helper::DEFAULT_SOCKET)]
+        socket: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum LanguageAction {
+    /// Switch to the next configured language
+    Next,
+
+    /// Switch to the given language
+    Set {
+        /// Language name, as listed in `languages` in config.toml
+        language: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ServiceAction {
+    /// Generate the unit file, then enable and start it
+    Install {
+        /// Dry-run mode: show the unit file without installing it
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Install the input helper as a system service instead (run with sudo)
+        #[arg(long)]
+        helper: bool,
+    },
+
+    /// Disable the service and remove its unit file
+    Uninstall {
+        /// Remove the input helper's system service instead (run with sudo)
+        #[arg(long)]
+        helper: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum SandboxAction {
+    /// Sandbox this process like the daemon and probe what is allowed
+    Check,
+}
+
+#[derive(Subcommand)]
+enum RulesAction {
+    /// Dry-run a script against recorded events and show its decisions
+    Test {
+        /// Script to test instead of the configured one
+        #[arg(long)]
+        script: Option<PathBuf>,
+
+        /// JSON lines file of events, as recorded with `record = true`
+        #[arg(long)]
+        events: Option<PathBuf>,
+    },
 }
 
 struct MonitoredKeyboard {
-    name:        String,
-    task_handle: JoinHandle<()>,
+    name:         String,
+    layout_index: u32,
+    index:        DeviceIndex, // reactor token
+}
+
+/// What the input reactor keeps for each keyboard: its "vid:pid" and slot
+type KeyboardInput = (String, Arc<activity::DeviceSlot>);
+
+/// First delay before reopening a keyboard whose input stopped; doubled on
+/// every failed attempt, up to `REOPEN_MAX_DELAY`.
+const REOPEN_DELAY: Duration = Duration::from_millis(500);
+const REOPEN_MAX_DELAY: Duration = Duration::from_secs(30);
+/// After this many attempts the keyboard is left to the hotplug monitor.
+const REOPEN_ATTEMPTS: u32 = 10;
+
+/// Time to let keyboards re-enumerate after the system resumes from sleep.
+const RESUME_SETTLE: Duration = Duration::from_secs(1);
+
+/// Time to let the kernel and udev set up a device after a USB change.
+/// Without it, the input reactor may not receive events from a newly
+/// plugged device.
+const HOTPLUG_SETTLE: Duration = Duration::from_millis(500);
+
+/// How often an empty config is checked again while waiting for setup.
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// What requests other than status get while waiting for setup
+const WAITING_FOR_CONFIG: &str = "No keyboards configured yet, run 'kunai setup'";
+
+/// Longest timed pause; pause without a timeout to stop for longer.
+const MAX_PAUSE_SECS: u64 = 365 * 24 * 3600;
+
+/// When a pause of `timeout_secs` starting at `now` ends.
+fn pause_deadline(now: Instant, timeout_secs: u64) -> Result<Instant, String> {
+    if timeout_secs > MAX_PAUSE_SECS {
+        return Err(format!(
+            "Pause timeout of {}s is too long (at most {}s); leave it out to pause until resumed",
+            timeout_secs, MAX_PAUSE_SECS
+        ));
+    }
+    now.checked_add(Duration::from_secs(timeout_secs))
+        .ok_or_else(|| format!("Pause timeout of {}s is too long", timeout_secs))
+}
+
+/// Retry state for a keyboard that could not be opened, or whose input
+/// stream ended while it was monitored (suspend/resume, USB reset, Bluetooth
+/// dropout).
+struct Reopen {
+    name:     String,
+    attempts: u32,
+    next_at:  Instant,
+}
+
+/// Keyboards that started or stopped being monitored in one re-enumeration.
+#[derive(Default)]
+struct MonitorChanges {
+    connected:    Vec<String>,
+    disconnected: Vec<String>,
 }
 
 struct DaemonState {
-    layout_map:          HashMap<String, (String, u32)>, // "vid:pid" -> (name, layout_idx)
+    keyboards:           HashMap<String, KeyboardConfig>, // "vid:pid" -> keyboard config
     monitored_keyboards: HashMap<String, MonitoredKeyboard>, // "vid:pid" -> monitor info
+    default_layout:      Option<u32>,                     // layout for unconfigured keyboards
+
+    started_at:     Instant,
+    dry_run:        bool,
+    paused:         bool,
+    resume_at:      Option<Instant>,
+    last_switch:    Option<ipc::SwitchInfo>,
+    last_switch_at: Option<Instant>,
+
+    policy:           policy::SwitchPolicy,
+    sticky:           Option<StickyMode>,
+    current_layout:   Option<u32>,
+    expected_layouts: VecDeque<u32>, // niri layout events caused by our own switches
+
+    languages: Vec<String>,
+    language:  Option<String>, // current logical language, if languages are configured
+
+    app_rules:           Vec<AppRule>,
+    app_rule_mode:       RuleMode,
+    workspace_rules:     Vec<WorkspaceRule>,
+    workspace_rule_mode: RuleMode,
+    focused_window:      Option<niri::Window>,
+    focused_workspace:   Option<niri::Workspace>,
+    rule_layout_applied: bool, // the current layout came from a rule or window memory
+
+    remember_window_layouts: bool,
+    window_layouts:          HashMap<u64, u32>, // niri window id -> layout it last used
+
+    script:      Option<script::RuleScript>,
+    hooks:       hooks::Hooks,
+    switch_hook: Option<hooks::Event>, // fired once the pending switch is done
+    switcher:    Option<switcher::Switcher>, // None in dry-run mode
+
+    switch_pending:        Option<u64>, // latest requested switch, until the worker reports back
+    layouts_during_switch: VecDeque<u32>, // niri layout events seen while a switch ran
+
+    activity:         Arc<activity::Activity>, // key press state shared with the monitors
+    devices:          activity::Interner,
+    reactor:          reactor::Reactor<KeyboardInput>,
+    reopening:        HashMap<String, Reopen>, // "vid:pid" -> retry state
+    helper_keyboards: Option<Vec<ipc::KeyboardStatus>>, // keyboards the input helper reads
+
+    sleeping:       bool, // between logind's PrepareForSleep(true) and (false)
+    session_active: bool, // our logind session is in the foreground
+    rescan_at:      Option<Instant>, // full keyboard rescan after resuming
+    hotplug_at:     Option<Instant>, // rescan once a USB change has settled
+
+    layout_names:   Vec<String>, // niri's layouts, by index
+    snapshot:       tokio::sync::watch::Sender<ipc::Snapshot>, // what `kunai watch` sees
+    hotplug_filter: Arc<RwLock<input::HotplugFilter>>, // what the USB monitor reports
+}
+
+/// What caused a layout switch
+#[derive(Clone, Copy)]
+enum Trigger<'a> {
+    Keyboard(&'a str),  // "vid:pid"
+    Window(&'a str),    // app_id or title
+    Workspace(&'a str), // workspace name or index
+    Idle,
+    Resync,
+}
+
+impl std::fmt::Display for Trigger<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Trigger::Keyboard(device_id) => write!(f, "device {}", device_id),
+            Trigger::Window(app) => write!(f, "window {}", app),
+            Trigger::Workspace(workspace) => write!(f, "workspace {}", workspace),
+            Trigger::Idle => write!(f, "idle timeout"),
+            Trigger::Resync => write!(f, "resync"),
+        }
+    }
+}
+
+impl DaemonState {
+    fn pause(&mut self, timeout_secs: Option<u64>) -> ipc::Response {
+        let resume_at = match timeout_secs
+            .map(|secs| pause_deadline(Instant::now(), secs))
+            .transpose()
+        {
+            Ok(resume_at) => resume_at,
+            Err(message) => return ipc::Response::Error { message },
+        };
+        self.paused = true;
+        self.resume_at = resume_at;
+        match timeout_secs {
+            Some(secs) => info!("Layout switching paused for {}s", secs),
+            None => info!("Layout switching paused"),
+        }
+        self.hooks.fire(hooks::Event::new(HookEvent::Pause));
+        ipc::Response::Ok
+    }
+
+    fn resume(&mut self) {
+        if self.paused {
+            info!("Layout switching resumed");
+            self.hooks.fire(hooks::Event::new(HookEvent::Resume));
+        }
+        self.paused = false;
+        self.resume_at = None;
+    }
+
+    /// The monitored keyboards, by device ID.
+    fn keyboards(&self) -> Vec<ipc::KeyboardStatus> {
+        let mut keyboards: Vec<ipc::KeyboardStatus> = self
+            .monitored_keyboards
+            .iter()
+            .map(|(id, kb)| ipc::KeyboardStatus {
+                device_id: id.clone(),
+                name:      kb.name.clone(),
+            })
+            .collect();
+        keyboards.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+        keyboards
+    }
+
+    fn status(&self) -> ipc::StatusInfo {
+        let mut reopening: Vec<ipc::KeyboardStatus> = self
+            .reopening
+            .iter()
+            .map(|(id, reopen)| ipc::KeyboardStatus {
+                device_id: id.clone(),
+                name:      reopen.name.clone(),
+            })
+            .collect();
+        reopening.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+
+        ipc::StatusInfo {
+            pid: std::process::id(),
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            dry_run: self.dry_run,
+            paused: self.paused,
+            resume_in_secs: self
+                .resume_at
+                .map(|at| at.saturating_duration_since(Instant::now()).as_secs()),
+            keyboards: self.keyboards(),
+            reopening,
+            last_switch: self.last_switch.clone(),
+            language: self.language.clone(),
+            waiting_for_config: false,
+        }
+    }
+
+    /// The state status bars show.
+    fn snapshot(&self) -> ipc::Snapshot {
+        ipc::Snapshot {
+            active:       self.policy.active().map(|device_id| ipc::KeyboardStatus {
+                device_id: device_id.to_string(),
+                name:      self
+                    .keyboard_name(device_id)
+                    .unwrap_or_default()
+                    .to_string(),
+            }),
+            layout_index: self.current_layout,
+            layout:       self
+                .current_layout
+                .and_then(|layout| self.layout_names.get(layout as usize).cloned()),
+            paused:       self.paused,
+            keyboards:    self.keyboards(),
+        }
+    }
+
+    /// Tell watchers, and the snapshot file, if what they show changed.
+    fn publish_snapshot(&self) {
+        let snapshot = self.snapshot();
+        let changed = self.snapshot.send_if_modified(|current| {
+            if *current == snapshot {
+                return false;
+            }
+            *current = snapshot.clone();
+            true
+        });
+        if changed && let Err(e) = ipc::write_snapshot(&snapshot) {
+            warn!("Failed to write snapshot file: {}", e);
+        }
+    }
+
+    fn handle_request(&mut self, request: ipc::Request) -> ipc::Response {
+        match request {
+            ipc::Request::Status => ipc::Response::Status(self.status()),
+            ipc::Request::Pause { timeout_secs } => self.pause(timeout_secs),
+            ipc::Request::Resume => {
+                self.resume();
+                ipc::Response::Ok
+            }
+            ipc::Request::Cycle { reverse } => self.cycle_layout(reverse),
+            ipc::Request::NextLanguage => self.set_language(None),
+            ipc::Request::SetLanguage { language } => self.set_language(Some(language)),
+            ipc::Request::Reload => self.reload(),
+            ipc::Request::SetLayout { keyboard, layout } => {
+                self.set_keyboard_layout(&keyboard, layout)
+            }
+            ipc::Request::Rescan => self.rescan(),
+            ipc::Request::Watch => ipc::Response::Error {
+                message: "Watch is not a one-off request".to_string(),
+            },
+        }
+    }
+
+    /// Re-read config.toml and apply it. The input helper, sandbox and idle
+    /// timeout only change on restart.
+    fn reload(&mut self) -> ipc::Response {
+        let config = match Config::load() {
+            Ok(config) => config,
+            Err(e) => {
+                let message = match restart_needed(None) {
+                    Some(reason) => format!("Could not load config: {} ({})", e, reason),
+                    None => format!("Could not load config: {}", e),
+                };
+                return ipc::Response::Error { message };
+            }
+        };
+        if let Some(reason) = restart_needed(Some(&config)) {
+            warn!("Not reloading: {}", reason);
+            return ipc::Response::Error {
+                message: format!("Could not apply config: {}", reason),
+            };
+        }
+        let script = match &config.script.path {
+            Some(path) => match script::RuleScript::load(
+                path,
+                Duration::from_millis(config.script.budget_ms),
+                config.script.record,
+            ) {
+                Ok(script) => Some(script),
+                Err(e) => {
+                    return ipc::Response::Error {
+                        message: format!("Could not load rules script: {}", e),
+                    };
+                }
+            },
+            None => None,
+        };
+
+        self.keyboards = keyboards_by_id(&config);
+        self.default_layout = config.default_layout;
+        self.sticky = config.sticky;
+        if self
+            .language
+            .as_ref()
+            .is_none_or(|language| !config.languages.contains(language))
+        {
+            self.language = config.languages.first().cloned();
+        }
+        self.languages = config.languages.clone();
+        self.app_rules = config.app_rules.clone();
+        self.app_rule_mode = config.app_rule_mode;
+        self.workspace_rules = config.workspace_rules.clone();
+        self.workspace_rule_mode = config.workspace_rule_mode;
+        self.remember_window_layouts = config.remember_window_layouts;
+        self.script = script;
+        self.hooks = hooks::Hooks::spawn(&config.hooks, self.dry_run);
+        if let Ok(mut filter) = self.hotplug_filter.write() {
+            *filter = input::HotplugFilter::new(&config);
+        }
+
+        let active = self.policy.active().map(str::to_string);
+        self.policy = policy::SwitchPolicy::new(&config.policy, priorities(&config));
+
+        // Keyboards that stay get their new layout; the ones no longer
+        // configured are let go
+        self.monitored_keyboards.retain(|device_id, monitor| {
+            let layout = match self.keyboards.get(device_id) {
+                Some(config) => {
+                    monitor.name = config.name.clone();
+                    Some(config.layout_index)
+                }
+                None => self.default_layout,
+            };
+            match layout {
+                Some(layout) => {
+                    monitor.layout_index = layout;
+                    true
+                }
+                None => {
+                    let _ = self.reactor.remove(monitor.index.into());
+                    info!("Stopped monitoring: {} ({})", monitor.name, device_id);
+                    false
+                }
+            }
+        });
+        if !self.input_suspended() {
+            match manage_keyboard_monitors(self) {
+                Ok(mut changes) => self.settle_reopened(&mut changes),
+                Err(e) => {
+                    return ipc::Response::Error {
+                        message: format!("Could not open keyboards: {}", e),
+                    };
+                }
+            }
+        }
+
+        if let Some(active) = active.filter(|id| self.monitored_keyboards.contains_key(id)) {
+            self.policy.activate(&active, Instant::now());
+        }
+        info!("Configuration reloaded");
+        self.resync();
+        ipc::Response::Ok
+    }
+
+    /// Use `layout` for `keyboard` ("vid:pid" or name) until the daemon
+    /// exits, switching to it right away if that keyboard is active.
+    fn set_keyboard_layout(&mut self, keyboard: &str, layout: u32) -> ipc::Response {
+        let device_id = self
+            .monitored_keyboards
+            .iter()
+            .find(|(id, kb)| id.as_str() == keyboard || kb.name == keyboard)
+            .map(|(id, _)| id.clone())
+            .or_else(|| {
+                self.keyboards
+                    .iter()
+                    .find(|(id, kb)| id.as_str() == keyboard || kb.name == keyboard)
+                    .map(|(id, _)| id.clone())
+            });
+        let Some(device_id) = device_id else {
+            return ipc::Response::Error {
+                message: format!("Unknown keyboard {:?}", keyboard),
+            };
+        };
+        if !self.layout_names.is_empty() && layout as usize >= self.layout_names.len() {
+            return ipc::Response::Error {
+                message: format!("No layout {}; niri has {}", layout, self.layout_names.len()),
+            };
+        }
+
+        match self.remember_layout(&device_id, layout) {
+            Some(language) => info!("Layout {} set for {} in {}", layout, device_id, language),
+            None => info!("Layout {} set for {}", layout, device_id),
+        }
+
+        if self.policy.active() == Some(device_id.as_str())
+            && !self.paused
+            && !self.rule_overrides()
+            && let Some(target) = self.target_layout(&device_id)
+            && self.current_layout != Some(target)
+        {
+            self.apply_layout(Trigger::Keyboard(&device_id), target);
+        }
+        ipc::Response::Ok
+    }
+
+    /// Re-enumerate keyboards, as if a USB device had come or gone.
+    fn rescan(&mut self) -> ipc::Response {
+        if self.input_suspended() {
+            return ipc::Response::Error {
+                message: "Keyboards are closed while the system sleeps or the session is inactive"
+                    .to_string(),
+            };
+        }
+        match manage_keyboard_monitors(self) {
+            Ok(mut changes) => {
+                self.settle_reopened(&mut changes);
+                handle_monitor_changes(self, &changes);
+                ipc::Response::Ok
+            }
+            Err(e) => ipc::Response::Error {
+                message: format!("Could not open keyboards: {}", e),
+            },
+        }
+    }
+
+    /// The reactor stopped reading keyboard `index`. Forget its monitor so it
+    /// can be reopened, and schedule the first attempt.
+    fn on_monitor_closed(&mut self, index: DeviceIndex) {
+        let device_id = self.devices.device_id(index).to_string();
+        let Some(monitor) = self.monitored_keyboards.remove(&device_id) else {
+            return;
+        };
+
+        warn!(
+            "Lost input from {} ({}), reopening it",
+            monitor.name, device_id
+        );
+        self.schedule_reopen(device_id, monitor.name);
+    }
+
+    /// Retry opening `device_id` later, unless that is already scheduled.
+    fn schedule_reopen(&mut self, device_id: String, name: String) {
+        self.reopening.entry(device_id).or_insert(Reopen {
+            name,
+            attempts: 0,
+            next_at: Instant::now() + REOPEN_DELAY,
+        });
+    }
+
+    /// True while keyboards are closed because the system sleeps or our
+    /// session is in the background. No layouts are switched meanwhile.
+    fn input_suspended(&self) -> bool {
+        self.sleeping || !self.session_active
+    }
+
+    /// Stop reading every keyboard, e.g. before the system sleeps: their
+    /// file descriptors rarely survive it.
+    fn quiesce_monitors(&mut self) {
+        for (device_id, monitor) in self.monitored_keyboards.drain() {
+            if let Err(e) = self.reactor.remove(monitor.index.into()) {
+                warn!("Failed to stop monitoring {}: {}", device_id, e);
+            }
+            debug!("Stopped monitoring: {} ({})", monitor.name, device_id);
+        }
+        self.reopening.clear();
+        // Everything is rescanned once input resumes
+        self.hotplug_at = None;
+    }
+
+    /// Re-apply the layout that should be active now, after something
+    /// outside kunai (sleep, another session) may have changed it.
+    fn resync(&mut self) {
+        if self.paused {
+            return;
+        }
+        self.expected_layouts.clear();
+
+        if let Some((layout, _)) = self.rule_layout() {
+            self.apply_layout(Trigger::Resync, layout);
+            self.rule_layout_applied = true;
+        } else if let Some(device_id) = self.policy.active().map(str::to_string)
+            && let Some(layout) = self.target_layout(&device_id)
+        {
+            self.apply_layout(Trigger::Keyboard(&device_id), layout);
+        }
+    }
+
+    /// Count a key press reported by the input helper on keyboard `index`,
+    /// if it is one we monitor.
+    fn helper_key_pressed(&self, index: Option<DeviceIndex>, tx: &mpsc::Sender<DeviceIndex>) {
+        let Some(index) = index else {
+            return;
+        };
+        if self.input_suspended()
+            || !self
+                .monitored_keyboards
+                .contains_key(self.devices.device_id(index))
+        {
+            return;
+        }
+        record_press(&self.activity, tx, index, self.devices.slot(index));
+    }
+
+    /// Name of a monitored or configured keyboard.
+    fn keyboard_name(&self, device_id: &str) -> Option<&str> {
+        self.monitored_keyboards
+            .get(device_id)
+            .map(|kb| kb.name.as_str())
+            .or_else(|| self.keyboards.get(device_id).map(|kb| kb.name.as_str()))
+    }
+
+    /// When the next keyboard is due to be reopened.
+    fn next_reopen(&self) -> Option<Instant> {
+        self.reopening.values().map(|reopen| reopen.next_at).min()
+    }
+
+    /// Drop keyboards that were just reopened from `changes` (they are back,
+    /// not newly connected) and push back the ones still missing.
+    fn settle_reopened(&mut self, changes: &mut MonitorChanges) {
+        changes.connected.retain(|device_id| {
+            if self.reopening.remove(device_id).is_none() {
+                return true;
+            }
+            info!("Reopened {}", device_id);
+            false
+        });
+
+        let now = Instant::now();
+        self.reopening.retain(|device_id, reopen| {
+            if reopen.next_at > now {
+                return true;
+            }
+            reopen.attempts += 1;
+            if reopen.attempts >= REOPEN_ATTEMPTS {
+                warn!(
+                    "Giving up on reopening {}; it will be picked up when plugged in again",
+                    device_id
+                );
+                return false;
+            }
+            let delay = REOPEN_DELAY.saturating_mul(1 << reopen.attempts.min(16));
+            reopen.next_at = now + delay.min(REOPEN_MAX_DELAY);
+            debug!(
+                "{} not readable yet, retrying in {:?}",
+                device_id,
+                reopen.next_at - now
+            );
+            true
+        });
+    }
+
+    /// Let the monitors know which keyboard is active, so they stop waking
+    /// the loop for its key presses.
+    fn publish_active(&self) {
+        let index = self
+            .policy
+            .active()
+            .and_then(|device_id| self.devices.get(device_id));
+        self.activity.set_active(index);
+    }
+
+    /// The layout to apply when `device_id` becomes active: its mapping for
+    /// the current language if it has one, otherwise its own layout.
+    fn target_layout(&self, device_id: &str) -> Option<u32> {
+        let kb = self.monitored_keyboards.get(device_id)?;
+        let mapped = self.mapped_language(device_id).map(|(_, layout)| layout);
+        Some(mapped.unwrap_or(kb.layout_index))
+    }
+
+    /// The current language and its layout, if `device_id` maps it.
+    fn mapped_language(&self, device_id: &str) -> Option<(&str, u32)> {
+        let language = self.language.as_deref()?;
+        let layout = self
+            .keyboards
+            .get(device_id)?
+            .language_layouts
+            .get(language)?;
+        Some((language, *layout))
+    }
+
+    /// Make `layout` the one `target_layout` picks for `device_id`, changing
+    /// whichever is in effect: the mapping for the current language, or the
+    /// keyboard's own layout. Returns the language if it was the mapping.
+    fn remember_layout(&mut self, device_id: &str, layout: u32) -> Option<String> {
+        if let Some((language, _)) = self.mapped_language(device_id) {
+            let language = language.to_string();
+            if let Some(config) = self.keyboards.get_mut(device_id) {
+                config.language_layouts.insert(language.clone(), layout);
+            }
+            return Some(language);
+        }
+
+        if let Some(kb) = self.monitored_keyboards.get_mut(device_id) {
+            kb.layout_index = layout;
+        }
+        if let Some(config) = self.keyboards.get_mut(device_id) {
+            config.layout_index = layout;
+        }
+        None
+    }
+
+    /// Change the global language and re-apply the active keyboard's layout
+    /// for it.
+    fn set_language(&mut self, language: Option<String>) -> ipc::Response {
+        let language = match language {
+            Some(language) if self.languages.contains(&language) => language,
+            Some(language) => {
+                return ipc::Response::Error {
+                    message: format!(
+                        "Unknown language {:?} (configured: {})",
+                        language,
+                        self.languages.join(", ")
+                    ),
+                };
+            }
+            // Next language, wrapping around
+            None => {
+                let Some(pos) = self
+                    .language
+                    .as_ref()
+                    .and_then(|current| self.languages.iter().position(|l| l == current))
+                else {
+                    return ipc::Response::Error {
+                        message: "No languages configured".to_string(),
+                    };
+                };
+                self.languages[(pos + 1) % self.languages.len()].clone()
+            }
+        };
+
+        info!("Language set to {}", language);
+        self.language = Some(language);
+
+        if let Some(device_id) = self.policy.active().map(str::to_string)
+            && let Some(layout) = self.target_layout(&device_id)
+            && !self.paused
+            && !self.rule_overrides()
+        {
+            self.apply_layout(Trigger::Keyboard(&device_id), layout);
+        }
+
+        ipc::Response::Ok
+    }
+
+    /// Step the active keyboard to the next (or previous) layout in its
+    /// allowed set and remember that position for the keyboard (for the
+    /// current language, if it maps one).
+    fn cycle_layout(&mut self, reverse: bool) -> ipc::Response {
+        let Some(device_id) = self.policy.active().map(str::to_string) else {
+            return ipc::Response::Error {
+                message: "No keyboard is active yet".to_string(),
+            };
+        };
+        let Some(current) = self.target_layout(&device_id) else {
+            return ipc::Response::Error {
+                message: format!("Keyboard {} is not monitored", device_id),
+            };
+        };
+
+        let allowed = match self.keyboards.get(&device_id) {
+            Some(config) => config.allowed_layouts(),
+            None => vec![current],
+        };
+        let next = match allowed.iter().position(|l| *l == current) {
+            Some(pos) if reverse => allowed[(pos + allowed.len() - 1) % allowed.len()],
+            Some(pos) => allowed[(pos + 1) % allowed.len()],
+            None => allowed[0],
+        };
+
+        if next != current {
+            self.apply_layout(Trigger::Keyboard(&device_id), next);
+        }
+        self.remember_layout(&device_id, next);
+
+        ipc::Response::Ok
+    }
+
+    fn record_switch(&mut self, trigger: Trigger, layout_index: u32) {
+        let (device_id, name) = match trigger {
+            Trigger::Keyboard(id) => (
+                id.to_string(),
+                self.monitored_keyboards
+                    .get(id)
+                    .map(|kb| kb.name.clone())
+                    .unwrap_or_default(),
+            ),
+            other => (String::new(), other.to_string()),
+        };
+        self.last_switch = Some(ipc::SwitchInfo {
+            device_id,
+            name,
+            layout_index,
+            at: chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        });
+    }
+
+    /// Hand a switch to `layout` to the switch worker (or just log it in
+    /// dry-run mode). Failures are reported back through `on_switch_done`.
+    fn apply_layout(&mut self, trigger: Trigger, layout: u32) {
+        if self.input_suspended() {
+            debug!(
+                "Keyboards are closed, not switching to layout {} for {}",
+                layout, trigger
+            );
+            return;
+        }
+        let mut hook = hooks::Event::new(HookEvent::Switch).layout(layout);
+        if let Trigger::Keyboard(device_id) = trigger {
+            hook = hook.device(device_id, self.keyboard_name(device_id));
+        }
+
+        match &mut self.switcher {
+            Some(switcher) if !self.dry_run => {
+                let seq = switcher.request(layout, matches!(trigger, Trigger::Keyboard(_)));
+                self.switch_pending = Some(seq);
+                self.switch_hook = Some(hook);
+                debug!("Requested layout {} for {}", layout, trigger);
+            }
+            _ => {
+                info!(
+                    "[DRY-RUN] Would switch to layout {} for {}",
+                    layout, trigger
+                );
+                self.hooks.fire(hook);
+            }
+        }
+        self.current_layout = Some(layout);
+        self.last_switch_at = Some(Instant::now());
+        self.rule_layout_applied = matches!(trigger, Trigger::Window(_) | Trigger::Workspace(_));
+        self.record_switch(trigger, layout);
+    }
+
+    /// The switch worker finished a job. Remember which niri layout events
+    /// it caused and, if the latest switch failed, undo its bookkeeping.
+    fn on_switch_done(&mut self, outcome: switcher::Outcome) {
+        let latest = self.switch_pending == Some(outcome.job.seq);
+        if latest {
+            self.switch_pending = None;
+        }
+
+        match outcome.result {
+            Ok(visited) => {
+                debug!("Switched to layout {}", outcome.job.layout);
+                if latest
+                    && !visited.is_empty()
+                    && let Some(hook) = self.switch_hook.take()
+                {
+                    self.hooks.fire(hook);
+                }
+                self.expected_layouts.extend(visited);
+            }
+            Err(e) => {
+                error!("Failed to switch layout: {}", e);
+                if latest {
+                    self.current_layout = None;
+                    if outcome.job.from_keyboard {
+                        self.policy.switch_failed();
+                    }
+                }
+            }
+        }
+
+        // Events niri already sent for this switch while it was running
+        while let (Some(seen), Some(expected)) = (
+            self.layouts_during_switch.front(),
+            self.expected_layouts.front(),
+        ) && seen == expected
+        {
+            self.layouts_during_switch.pop_front();
+            self.expected_layouts.pop_front();
+        }
+        if self.switch_pending.is_none() {
+            self.layouts_during_switch.clear();
+        }
+    }
+
+    /// Let the rules script override `default`, the layout the built-in
+    /// rules picked for `trigger` (`None` meaning no change). Without a
+    /// script, or if it fails or runs out of time, `default` stands.
+    fn decide(&mut self, trigger: Trigger, default: Option<u32>) -> Option<u32> {
+        if self.script.is_none() {
+            return default;
+        }
+
+        let (event, device_id, previous_device) = match trigger {
+            Trigger::Keyboard(id) => (
+                "keyboard",
+                Some(id.to_string()),
+                self.policy.previous().map(str::to_string),
+            ),
+            Trigger::Window(_) => ("window", self.policy.active().map(str::to_string), None),
+            Trigger::Workspace(_) => ("workspace", self.policy.active().map(str::to_string), None),
+            Trigger::Idle | Trigger::Resync => return default,
+        };
+        let context = script::Context {
+            event: event.to_string(),
+            device_name: device_id
+                .as_ref()
+                .and_then(|id| self.monitored_keyboards.get(id))
+                .map(|kb| kb.name.clone()),
+            device_id,
+            previous_device,
+            current_layout: self.current_layout,
+            default_layout: default,
+            app_id: self.focused_window.as_ref().and_then(|w| w.app_id.clone()),
+            title: self.focused_window.as_ref().and_then(|w| w.title.clone()),
+            workspace: self
+                .focused_workspace
+                .as_ref()
+                .map(|w| w.name.clone().unwrap_or_else(|| w.idx.to_string())),
+            output: self
+                .focused_workspace
+                .as_ref()
+                .and_then(|w| w.output.clone()),
+            language: self.language.clone(),
+            timestamp_ms: chrono::Local::now().timestamp_millis(),
+            ms_since_last_switch: self
+                .last_switch_at
+                .map(|at| at.elapsed().as_millis() as u64),
+        };
+
+        let script = self.script.as_mut()?;
+        match script.evaluate(&context) {
+            Ok(decision) => {
+                if decision != default {
+                    debug!(
+                        "Rules script chose {:?} instead of {:?} for {}",
+                        decision, default, trigger
+                    );
+                }
+                decision
+            }
+            Err(e) => {
+                warn!("{}; using the built-in decision", e);
+                default
+            }
+        }
+    }
+
+    /// The layout the focused window's app rule asks for, if any.
+    fn app_rule_layout(&self) -> Option<u32> {
+        let window = self.focused_window.as_ref()?;
+        self.app_rules
+            .iter()
+            .find(|rule| rule.matches(window.app_id.as_deref(), window.title.as_deref()))
+            .map(|rule| rule.layout_index)
+    }
+
+    /// The layout a rule for the focused workspace or its output asks for.
+    fn workspace_rule_layout(&self) -> Option<u32> {
+        let workspace = self.focused_workspace.as_ref()?;
+        self.workspace_rules
+            .iter()
+            .find(|rule| {
+                rule.matches(
+                    workspace.name.as_deref(),
+                    workspace.idx,
+                    workspace.output.as_deref(),
+                )
+            })
+            .map(|rule| rule.layout_index)
+    }
+
+    /// The layout rules ask for right now, and in which mode. App rules take
+    /// precedence over workspace rules, which take precedence over keyboards.
+    fn rule_layout(&self) -> Option<(u32, RuleMode)> {
+        self.app_rule_layout()
+            .map(|layout| (layout, self.app_rule_mode))
+            .or_else(|| {
+                self.workspace_rule_layout()
+                    .map(|layout| (layout, self.workspace_rule_mode))
+            })
+    }
+
+    /// True while a rule in override mode pins the layout, so keyboard
+    /// changes must not switch it.
+    fn rule_overrides(&self) -> bool {
+        matches!(self.rule_layout(), Some((_, RuleMode::Override)))
+    }
+
+    /// niri moved focus to `window`. Apply its rule or remembered layout.
+    fn on_window_focused(&mut self, window: Option<niri::Window>) {
+        let previous = self.focused_window.take();
+        let same_window = previous.as_ref().map(|w| w.id) == window.as_ref().map(|w| w.id);
+
+        if self.remember_window_layouts
+            && let (Some(previous), Some(layout)) = (&previous, self.current_layout)
+        {
+            self.window_layouts.insert(previous.id, layout);
+        }
+        self.focused_window = window;
+
+        let remembered = self
+            .focused_window
+            .as_ref()
+            .filter(|_| self.remember_window_layouts && !same_window)
+            .and_then(|w| self.window_layouts.get(&w.id).copied());
+        let label = self
+            .focused_window
+            .as_ref()
+            .and_then(|w| w.app_id.clone().or_else(|| w.title.clone()))
+            .unwrap_or_default();
+
+        self.apply_focus_layout(same_window, remembered, Trigger::Window(&label));
+    }
+
+    /// niri focused another workspace. Apply its (or its output's) rule.
+    fn on_workspace_focused(&mut self, workspace: Option<niri::Workspace>) {
+        let same_workspace =
+            self.focused_workspace.as_ref().map(|w| w.id) == workspace.as_ref().map(|w| w.id);
+        self.focused_workspace = workspace;
+
+        let label = self
+            .focused_workspace
+            .as_ref()
+            .map(|w| w.name.clone().unwrap_or_else(|| w.idx.to_string()))
+            .unwrap_or_default();
+
+        self.apply_focus_layout(same_workspace, None, Trigger::Workspace(&label));
+    }
+
+    /// Re-evaluate rules after focus moved: a matching rule first, then the
+    /// window's remembered layout. When leaving a rule-driven layout, go back
+    /// to the active keyboard's.
+    fn apply_focus_layout(&mut self, same_focus: bool, remembered: Option<u32>, trigger: Trigger) {
+        if self.paused {
+            return;
+        }
+
+        let default = match (self.rule_layout(), remembered) {
+            (Some((layout, _)), _) | (None, Some(layout)) => Some(layout),
+            (None, None) if self.rule_layout_applied && !same_focus => self
+                .policy
+                .active()
+                .and_then(|device_id| self.target_layout(device_id)),
+            (None, None) => None,
+        };
+        let Some(layout) = self.decide(trigger, default) else {
+            return;
+        };
+
+        if self.current_layout != Some(layout) {
+            self.apply_layout(trigger, layout);
+        }
+    }
+
+    /// niri reported a layout change. Changes we didn't cause are manual
+    /// switches; in sticky mode they are remembered for `active`, the
+    /// keyboard currently being typed on (for the current language, if it
+    /// maps one).
+    fn on_layout_switched(&mut self, layout: u32) {
+        self.current_layout = Some(layout);
+        if self.expected_layouts.front() == Some(&layout) {
+            self.expected_layouts.pop_front();
+            return;
+        }
+        if self.switch_pending.is_some() {
+            // Most likely our own switch; sorted out once the worker reports
+            self.layouts_during_switch.push_back(layout);
+            return;
+        }
+        self.expected_layouts.clear();
+
+        let (Some(mode), Some(device_id)) = (self.sticky, self.policy.active()) else {
+            return;
+        };
+        let device_id = device_id.to_string();
+        if self.paused {
+            return;
+        }
+        if self
+            .target_layout(&device_id)
+            .is_none_or(|target| target == layout)
+        {
+            return;
+        }
+
+        info!(
+            "Manual switch to layout {} while typing on {}, remembering it",
+            layout,
+            self.keyboard_name(&device_id).unwrap_or(&device_id)
+        );
+        let language = self.remember_layout(&device_id, layout);
+
+        if mode == StickyMode::Persist {
+            match Config::persist_layout(&device_id, language.as_deref(), layout) {
+                Ok(true) => info!("Saved layout {} for {} to config", layout, device_id),
+                Ok(false) => debug!(
+                    "{} is not configured, remembering its layout for this session only",
+                    device_id
+                ),
+                Err(e) => warn!("Failed to save learned layout: {}", e),
+            }
+        }
+    }
 }
 
 /// Redirect stdin stdout and stderr to `/dev/null` as we dont care for these values when forking
@@ -105,6 +1238,7 @@ fn write_to_devnull() -> Result<()> {
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
+    instance::set_name(&cli.instance)?;
 
     match cli.command {
         Commands::Daemon { dry_run, restart } if restart => {
@@ -140,14 +1274,19 @@ fn main() -> Result<()> {
 
                     info!("Daemon forked to background (PID {})", std::process::id());
 
-                    let runtime = tokio::runtime::Runtime::new()?;
-                    runtime.block_on(cmd_daemon(dry_run))
+                    run_daemon(dry_run)
                 }
                 Err(e) => {
                     anyhow::bail!("Failed to fork: {}", e);
                 }
             }
         }
+        Commands::Daemon { dry_run, .. } if systemd::stderr_is_journal() => {
+            // Started by systemd: log natively to the journal
+            init_journald_tracing()?;
+
+            run_daemon(dry_run)
+        }
         _ => {
             // All other commands: foreground with stderr tracing
             tracing_subscriber::fmt()
@@ -162,19 +1301,70 @@ fn main() -> Result<()> {
                 Commands::Setup { dry_run } => ui::wizard::run(dry_run),
                 Commands::Daemon { dry_run, .. } => {
                     // Foreground daemon (e.g. niri spawn-at-startup)
-                    let runtime = tokio::runtime::Runtime::new()?;
-                    runtime.block_on(cmd_daemon(dry_run))
+                    run_daemon(dry_run)
                 }
-                Commands::Test => {
+                Commands::Test => cmd_test(),
+                Commands::Dashboard => ui::dashboard::run(),
+                Commands::Status => cmd_status(),
+                Commands::Stop => cmd_stop(),
+                Commands::Watch { format } => watch::run(format),
+                Commands::Pause { timeout } => cmd_control(ipc::Request::Pause {
+                    timeout_secs: timeout,
+                }),
+                Commands::Resume => cmd_control(ipc::Request::Resume),
+                Commands::Cycle { reverse } => cmd_control(ipc::Request::Cycle { reverse }),
+                Commands::Language { action } => match action {
+                    LanguageAction::Next => cmd_control(ipc::Request::NextLanguage),
+                    LanguageAction::Set { language } => {
+                        cmd_control(ipc::Request::SetLanguage { language })
+                    }
+                },
+                Commands::Service { action } => match action {
+                    ServiceAction::Install { dry_run, helper } if helper => {
+                        systemd::install_helper(dry_run)
+                    }
+                    ServiceAction::Install { dry_run, .. } => systemd::install(dry_run),
+                    ServiceAction::Uninstall { helper } if helper => systemd::uninstall_helper(),
+                    ServiceAction::Uninstall { .. } => systemd::uninstall(),
+                },
+                Commands::Rules { action } => match action {
+                    RulesAction::Test { script, events } => {
+                        script::test(&Config::load()?.script, script, events)
+                    }
+                },
+                Commands::Sandbox { action } => match action {
+                    SandboxAction::Check => sandbox::check(),
+                },
+                Commands::Helper {
+                    allow_users,
+                    socket,
+                } => {
                     let runtime = tokio::runtime::Runtime::new()?;
-                    runtime.block_on(cmd_test())
+                    runtime.block_on(helper::run(&socket, &allow_users))
                 }
-                Commands::Dashboard => ui::dashboard::run(),
             }
         }
     }
 }
 
+/// Run the daemon on a new tokio runtime. With the sandbox enabled, the
+/// filesystem is restricted first: Landlock only covers threads created
+/// afterwards, such as the runtime's workers.
+fn run_daemon(dry_run: bool) -> Result<()> {
+    let config = Config::load()?;
+
+    // Landlock only covers threads created after it, so it goes on before
+    // the runtime starts; the seccomp filter follows once cmd_daemon has
+    // initialised
+    let sandboxed = config.sandbox.enabled;
+    if sandboxed {
+        sandbox::restrict_filesystem(&config)?;
+    }
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(cmd_daemon(config, dry_run, sandboxed))
+}
+
 /// Initialize tracing to write to a log file (used after daemonizing when
 /// stderr is redirected to /dev/null).
 fn init_file_tracing() -> Result<()> {
@@ -205,6 +1395,37 @@ fn init_file_tracing() -> Result<()> {
     Ok(())
 }
 
+/// Initialize tracing to send structured entries straight to journald (used
+/// when running as a systemd unit).
+fn init_journald_tracing() -> Result<()> {
+    use tracing_subscriber::{
+        layer::SubscriberExt,
+        util::SubscriberInitExt,
+    };
+
+    let journald = tracing_journald::layer()
+        .map_err(|e| anyhow::anyhow!("Failed to connect to journald: {}", e))?;
+
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::EnvFilter::from_default_env().add_directive(Level::INFO.into()))
+        .with(journald)
+        .try_init()
+        .map_err(|e| anyhow::anyhow!("Failed to set tracing subscriber: {}", e))?;
+
+    Ok(())
+}
+
+/// Shut the daemon down after an error it cannot recover from.
+fn exit_with_error(e: anyhow::Error) -> Result<()> {
+    error!("Failed to manage keyboard monitors: {}", e);
+    if let Err(dump_err) = write_error_dump(&e) {
+        error!("Failed to write error dump: {}", dump_err);
+    }
+    ipc::remove_socket();
+    ipc::remove_snapshot();
+    Err(e)
+}
+
 fn write_error_dump(error: &anyhow::Error) -> Result<()> {
     use std::io::Write;
 
@@ -220,6 +1441,7 @@ fn write_error_dump(error: &anyhow::Error) -> Result<()> {
         .open(&dump_path)?;
 
     writeln!(file, "\n========== ERROR DUMP {} ==========", timestamp)?;
+    writeln!(file, "Instance: {}", instance::name())?;
     writeln!(file, "Error: {:#}", error)?;
     writeln!(file, "\nBacktrace:")?;
     writeln!(file, "{:?}", error)?;
@@ -230,79 +1452,22 @@ fn write_error_dump(error: &anyhow::Error) -> Result<()> {
     Ok(())
 }
 
-// ---------------------------------------------------------------------------
-// PID file management
-// ---------------------------------------------------------------------------
-
-fn pid_file_path() -> Result<PathBuf> {
-    Ok(dirs::config_dir()
-        .ok_or_else(|| anyhow::anyhow!("Could not find config directory"))?
-        .join("kunai")
-        .join("daemon.pid"))
-}
-
-fn write_pid_file() -> Result<()> {
-    let path = pid_file_path()?;
-    std::fs::write(&path, std::process::id().to_string())?;
-    debug!("Wrote PID file: {}", path.display());
-    Ok(())
-}
-
-fn read_pid_file() -> Result<Option<nix::unistd::Pid>> {
-    let path = pid_file_path()?;
-    match std::fs::read_to_string(&path) {
-        Ok(contents) => match contents.trim().parse::<i32>() {
-            Ok(pid) => Ok(Some(nix::unistd::Pid::from_raw(pid))),
-            Err(_) => {
-                warn!("Invalid PID in {}: {:?}", path.display(), contents);
-                Ok(None)
-            }
-        },
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
-        Err(e) => Err(e.into()),
-    }
-}
-
-fn remove_pid_file() {
-    if let Ok(path) = pid_file_path()
-        && let Err(e) = std::fs::remove_file(&path)
-        && e.kind() != std::io::ErrorKind::NotFound
-    {
-        warn!("Failed to remove PID file: {}", e);
-    }
-}
-
-fn is_process_alive(pid: nix::unistd::Pid) -> bool {
-    // Signal 0 doesn't send a signal but checks if the process exists
-    nix::sys::signal::kill(pid, None).is_ok()
-}
-
 /// Kill a running daemon, waiting for it to exit. Returns Ok(true) if a daemon
 /// was killed, Ok(false) if no daemon was running.
 fn kill_running_daemon() -> Result<bool> {
-    let pid = match read_pid_file()? {
+    let pid = match instance::running_pid()? {
         Some(pid) => pid,
         None => return Ok(false),
     };
 
-    if !is_process_alive(pid) {
-        info!(
-            "Stale PID file found (process {} not running), cleaning up",
-            pid
-        );
-        remove_pid_file();
-        return Ok(false);
-    }
-
     info!("Sending SIGTERM to running daemon (PID {})", pid);
     nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGTERM)?;
 
     // Wait up to 2 seconds for the process to exit
     for _ in 0..20 {
         std::thread::sleep(Duration::from_millis(100));
-        if !is_process_alive(pid) {
+        if !instance::is_process_alive(pid) {
             info!("Daemon (PID {}) terminated gracefully", pid);
-            remove_pid_file();
             return Ok(true);
         }
     }
@@ -313,53 +1478,181 @@ fn kill_running_daemon() -> Result<bool> {
 
     // Brief wait for SIGKILL to take effect
     std::thread::sleep(Duration::from_millis(200));
-    remove_pid_file();
 
     Ok(true)
 }
 
-fn run_hotplug_monitor(
-    configured_devices: Arc<HashSet<(u16, u16)>>,
-    signal_tx: std::sync::mpsc::Sender<()>,
-) -> Result<()> {
-    let context = Context::new()?;
+fn format_duration(secs: u64) -> String {
+    let (h, m, s) = (secs / 3600, (secs % 3600) / 60, secs % 60);
+    if h > 0 {
+        format!("{}h {}m {}s", h, m, s)
+    } else if m > 0 {
+        format!("{}m {}s", m, s)
+    } else {
+        format!("{}s", s)
+    }
+}
+
+fn cmd_status() -> Result<()> {
+    let pid = match instance::running_pid()? {
+        Some(pid) => pid,
+        None => {
+            println!("○ Daemon is not running");
+            return Ok(());
+        }
+    };
 
-    let _reg: rusb::Registration<Context> = HotplugBuilder::new()
-        .enumerate(false) // Don't enumerate on registration
-        .register(
-            &context,
-            Box::new(HotPlugHandler {
-                configured_devices,
-                signal_tx,
-            }),
-        )?;
+    let status = match ipc::send_request(&ipc::Request::Status) {
+        Ok(ipc::Response::Status(status)) => status,
+        Ok(other) => anyhow::bail!("Unexpected response from daemon: {:?}", other),
+        Err(e) => {
+            println!(
+                "● Daemon is running (PID {}), but not responding: {}",
+                pid, e
+            );
+            return Ok(());
+        }
+    };
 
-    info!("USB hotplug monitoring started");
+    println!(
+        "● Daemon is running (PID {}){}",
+        status.pid,
+        if status.dry_run { " — DRY RUN" } else { "" }
+    );
+    println!("  Uptime:    {}", format_duration(status.uptime_secs));
 
-    loop {
-        if let Err(e) = context.handle_events(None) {
-            error!("USB context error: {}", e);
-            return Err(e.into());
+    if status.waiting_for_config {
+        println!("  Waiting for configuration, run 'kunai setup'");
+        return Ok(());
+    }
+
+    let state = match (status.paused, status.resume_in_secs) {
+        (false, _) => "active".to_string(),
+        (true, None) => "paused".to_string(),
+        (true, Some(secs)) => format!("paused (resumes in {})", format_duration(secs)),
+    };
+    println!("  Switching: {}", state);
+    if let Some(language) = &status.language {
+        println!("  Language:  {}", language);
+    }
+
+    match &status.last_switch {
+        Some(switch) => println!(
+            "  Last switch: {} ({}) → layout {} at {}",
+            switch.name, switch.device_id, switch.layout_index, switch.at
+        ),
+        None => println!("  Last switch: —"),
+    }
+
+    println!("  Monitored keyboards ({}):", status.keyboards.len());
+    for kb in &status.keyboards {
+        println!("    {} ({})", kb.name, kb.device_id);
+    }
+    if !status.reopening.is_empty() {
+        println!("  Reopening ({}):", status.reopening.len());
+        for kb in &status.reopening {
+            println!("    {} ({})", kb.name, kb.device_id);
+        }
+    }
+
+    Ok(())
+}
+
+fn cmd_stop() -> Result<()> {
+    if kill_running_daemon()? {
+        println!("✓ Daemon stopped");
+    } else {
+        println!("Daemon is not running");
+    }
+    Ok(())
+}
+
+/// Send a control request (pause, resume, cycle, language) to the running daemon.
+fn cmd_control(request: ipc::Request) -> Result<()> {
+    if instance::running_pid()?.is_none() {
+        anyhow::bail!("Daemon is not running");
+    }
+
+    let message = match &request {
+        ipc::Request::Pause {
+            timeout_secs: Some(secs),
+        } => format!("✓ Layout switching paused for {}", format_duration(*secs)),
+        ipc::Request::Pause { timeout_secs: None } => "✓ Layout switching paused".to_string(),
+        ipc::Request::Resume => "✓ Layout switching resumed".to_string(),
+        ipc::Request::SetLanguage { language } => format!("✓ Language set to {}", language),
+        ipc::Request::Status
+        | ipc::Request::Cycle { .. }
+        | ipc::Request::NextLanguage
+        | ipc::Request::Reload
+        | ipc::Request::SetLayout { .. }
+        | ipc::Request::Rescan
+        | ipc::Request::Watch => String::new(),
+    };
+
+    match ipc::send_request(&request)? {
+        ipc::Response::Ok => {
+            if !message.is_empty() {
+                println!("{}", message);
+            }
+            Ok(())
+        }
+        ipc::Response::Error { message } => anyhow::bail!("Daemon error: {}", message),
+        other => anyhow::bail!("Unexpected response from daemon: {:?}", other),
+    }
+}
+
+/// Sleep until the given deadline, or forever if there is none.
+async fn sleep_until_deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Wait for the next watchdog tick, or forever if the watchdog is disabled.
+async fn tick_watchdog(interval: &mut Option<tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
         }
+        None => std::future::pending().await,
     }
 }
 
-async fn manage_keyboard_monitors(
-    state: &mut DaemonState,
-    event_tx: mpsc::UnboundedSender<(String, u32)>,
-) -> Result<()> {
+/// Start monitoring new keyboards and stop monitoring removed ones. A
+/// keyboard that fails to open is retried later without affecting the
+/// others, so an error here means the daemon cannot go on.
+fn manage_keyboard_monitors(state: &mut DaemonState) -> Result<MonitorChanges> {
     info!("Re-enumerating keyboards...");
 
-    let current_keyboards = input::list_keyboards().map_err(|e| {
-        error!("Failed to enumerate keyboards: {}", e);
-        e
-    })?;
+    let mut changes = MonitorChanges::default();
+
+    // (device id, name, device to open); the input helper opens its own
+    let current_keyboards: Vec<(String, String, Option<PathBuf>)> = match &state.helper_keyboards {
+        Some(keyboards) => keyboards
+            .iter()
+            .map(|kb| (kb.device_id.clone(), kb.name.clone(), None))
+            .collect(),
+        None => match input::list_keyboards() {
+            Ok(keyboards) => keyboards
+                .into_iter()
+                .map(|kb| {
+                    let device_id = format!("{:04x}:{:04x}", kb.vendor_id, kb.product_id);
+                    (device_id, kb.name, Some(kb.device_path))
+                })
+                .collect(),
+            Err(e) => {
+                // Keep what we have; the next hotplug event tries again
+                warn!("Failed to enumerate keyboards: {}", e);
+                return Ok(changes);
+            }
+        },
+    };
 
     let mut current_device_ids: HashSet<String> = HashSet::new();
 
     // Start monitoring new keyboards
-    for kb in current_keyboards {
-        let device_id = format!("{:04x}:{:04x}", kb.vendor_id, kb.product_id);
+    for (device_id, kb_name, device_path) in current_keyboards {
         current_device_ids.insert(device_id.clone());
 
         // Skip if already monitoring
@@ -367,29 +1660,48 @@ async fn manage_keyboard_monitors(
             continue;
         }
 
-        // Check if device is in config
-        if let Some((name, layout_idx)) = state.layout_map.get(&device_id).cloned() {
-            let device = Device::open(&kb.device_path)?;
-            let stream = device.into_event_stream()?;
-            let tx = event_tx.clone();
-            let device_id_clone = device_id.clone();
-            let name_clone = name.clone();
-
-            let handle = tokio::spawn(async move {
-                info!("Started monitoring: {} → layout {}", name_clone, layout_idx);
-
-                monitor_keyboard(device_id_clone.clone(), layout_idx, stream, tx).await;
-
-                info!("Stopped monitoring: {} ({})", name_clone, device_id_clone);
+        // Check if device is in config, or fall back to the default layout
+        let target = state
+            .keyboards
+            .get(&device_id)
+            .map(|config| (config.name.clone(), config.layout_index))
+            .or_else(|| {
+                state
+                    .default_layout
+                    .map(|layout_idx| (kb_name.clone(), layout_idx))
             });
 
+        if let Some((name, layout_idx)) = target {
+            let device = match device_path.as_deref().map(reactor::open_device).transpose() {
+                Ok(device) => device,
+                Err(e) => {
+                    warn!(
+                        "Failed to open {} ({}): {}, retrying later",
+                        name, device_id, e
+                    );
+                    state.schedule_reopen(device_id, name);
+                    continue;
+                }
+            };
+            let index = state.devices.intern(&device_id);
+            let slot = state.devices.slot(index).clone();
+            slot.clear();
+            // Only fails if the reactor thread is gone
+            if let Some(device) = device {
+                state
+                    .reactor
+                    .add(index.into(), device, (device_id.clone(), slot))?;
+            }
+
             state.monitored_keyboards.insert(
                 device_id.clone(),
                 MonitoredKeyboard {
-                    name:        name.clone(),
-                    task_handle: handle,
+                    name: name.clone(),
+                    layout_index: layout_idx,
+                    index,
                 },
             );
+            changes.connected.push(device_id.clone());
 
             info!(
                 "Now monitoring: {} ({}) → layout {}",
@@ -408,83 +1720,339 @@ async fn manage_keyboard_monitors(
 
     for device_id in disconnected {
         if let Some(monitor) = state.monitored_keyboards.remove(&device_id) {
-            monitor.task_handle.abort(); // Cancel the monitoring task
+            state.reactor.remove(monitor.index.into())?;
             info!("Stopped monitoring: {} ({})", monitor.name, device_id);
+            changes.disconnected.push(device_id);
         }
     }
 
+    // Keyboards waiting to be reopened that are gone for good were unplugged
+    let unplugged: Vec<String> = state
+        .reopening
+        .keys()
+        .filter(|id| !current_device_ids.contains(*id))
+        .cloned()
+        .collect();
+    for device_id in unplugged {
+        state.reopening.remove(&device_id);
+        info!("{} was unplugged, no longer reopening it", device_id);
+        changes.disconnected.push(device_id);
+    }
+
     info!("Active monitors: {}", state.monitored_keyboards.len());
 
-    Ok(())
+    Ok(changes)
 }
 
-async fn cmd_daemon(dry_run: bool) -> Result<()> {
-    // Check for an already-running instance
-    if let Ok(Some(pid)) = read_pid_file() {
-        if is_process_alive(pid) {
-            anyhow::bail!(
-                "Daemon already running (PID {}). Use --restart to replace it.",
-                pid
-            );
-        } else {
-            info!(
-                "Stale PID file found (process {} not running), cleaning up",
-                pid
-            );
-            remove_pid_file();
+/// Configured keyboards by "vid:pid".
+fn keyboards_by_id(config: &Config) -> HashMap<String, KeyboardConfig> {
+    config
+        .keyboards
+        .iter()
+        .map(|kb| (kb.device_id(), kb.clone()))
+        .collect()
+}
+
+fn priorities(config: &Config) -> HashMap<String, i32> {
+    config
+        .keyboards
+        .iter()
+        .map(|kb| (kb.device_id(), kb.priority))
+        .collect()
+}
+
+/// Apply per-keyboard connect/disconnect behaviour after a hotplug
+/// re-enumeration.
+fn handle_monitor_changes(state: &mut DaemonState, changes: &MonitorChanges) {
+    for (kind, device_ids) in [
+        (HookEvent::Connect, &changes.connected),
+        (HookEvent::Disconnect, &changes.disconnected),
+    ] {
+        for device_id in device_ids {
+            let event = hooks::Event::new(kind).device(device_id, state.keyboard_name(device_id));
+            state.hooks.fire(event);
+        }
+    }
+
+    if state.paused {
+        return;
+    }
+
+    for device_id in &changes.disconnected {
+        if state.policy.active() != Some(device_id.as_str()) {
+            continue;
+        }
+        let restore = state
+            .keyboards
+            .get(device_id)
+            .is_some_and(|kb| kb.restore_on_disconnect);
+        let previous = state.policy.previous().map(str::to_string);
+
+        match previous {
+            Some(previous) if restore => {
+                let Some(layout) = state.target_layout(&previous) else {
+                    continue;
+                };
+                info!(
+                    "Active keyboard {} unplugged, restoring layout of {}",
+                    device_id, previous
+                );
+                state.policy.activate(&previous, Instant::now());
+                state.apply_layout(Trigger::Keyboard(&previous), layout);
+            }
+            // The next key press decides
+            _ => state.policy.reset(),
+        }
+    }
+
+    for device_id in &changes.connected {
+        let activate = state
+            .keyboards
+            .get(device_id)
+            .is_some_and(|kb| kb.activate_on_connect);
+        let Some(layout) = state.target_layout(device_id) else {
+            continue;
+        };
+
+        if activate {
+            info!("Keyboard {} connected, activating its layout", device_id);
+            state.policy.activate(device_id, Instant::now());
+            state.apply_layout(Trigger::Keyboard(device_id), layout);
+        }
+    }
+}
+
+/// Return `config` if it configures at least one keyboard (or a default
+/// layout), otherwise wait until the file does. It is checked again every
+/// few seconds and on SIGHUP, e.g. after `kunai setup`. Returns `None` on
+/// SIGTERM.
+/// Why the config (or, without one, the config file) can't be used until the
+/// daemon restarts: the sandbox was set up from the config read at startup
+/// and doesn't allow reading files it didn't name.
+fn restart_needed(config: Option<&Config>) -> Option<String> {
+    let paths = config::get_config_path()
+        .ok()
+        .into_iter()
+        .chain(config.and_then(|config| config.script.path.clone()));
+    let outside = sandbox::outside_ruleset(paths);
+    if outside.is_empty() {
+        return None;
+    }
+    let paths: Vec<String> = outside
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect();
+    Some(format!(
+        "the sandbox set up at startup does not allow reading {}; restart the daemon",
+        paths.join(", ")
+    ))
+}
+
+async fn wait_for_config(
+    config: Config,
+    notifier: Option<&systemd::Notifier>,
+    watchdog: &mut Option<tokio::time::Interval>,
+    sigterm: &mut tokio::signal::unix::Signal,
+    requests: &mut mpsc::UnboundedReceiver<ipc::Envelope>,
+    started_at: Instant,
+    dry_run: bool,
+) -> Result<Option<Config>> {
+    let mut sighup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .map_err(|e| anyhow::anyhow!("Failed to register SIGHUP handler: {}", e))?;
+    let mut last_error = None;
+
+    if !config.is_empty() {
+        return Ok(Some(config));
+    }
+    info!("No keyboards configured yet. Run 'kunai setup'; the daemon will pick it up.");
+    if let Some(notifier) = notifier {
+        notifier.ready("Waiting for configuration");
+    }
+
+    loop {
+        // A reload request is answered once the config has been read
+        let mut reload_reply = None;
+        tokio::select! {
+            _ = tokio::time::sleep(CONFIG_POLL_INTERVAL) => {}
+            _ = sighup.recv() => debug!("Received SIGHUP, reloading config"),
+            Some((request, reply_tx)) = requests.recv() => match request {
+                ipc::Request::Reload => reload_reply = Some(reply_tx),
+                ipc::Request::Status => {
+                    let _ = reply_tx.send(ipc::Response::Status(ipc::StatusInfo {
+                        pid: std::process::id(),
+                        uptime_secs: started_at.elapsed().as_secs(),
+                        dry_run,
+                        paused: false,
+                        resume_in_secs: None,
+                        keyboards: Vec::new(),
+                        reopening: Vec::new(),
+                        last_switch: None,
+                        language: None,
+                        waiting_for_config: true,
+                    }));
+                    continue;
+                }
+                _ => {
+                    let _ = reply_tx.send(ipc::Response::Error {
+                        message: WAITING_FOR_CONFIG.to_string(),
+                    });
+                    continue;
+                }
+            },
+            _ = tick_watchdog(watchdog) => {
+                if let Some(notifier) = notifier {
+                    notifier.watchdog();
+                }
+            }
+            _ = sigterm.recv() => {
+                if let Some(notifier) = notifier {
+                    notifier.stopping();
+                }
+                return Ok(None);
+            }
+        }
+
+        // This may catch a half-written file, so errors are only logged
+        let response = match Config::load() {
+            Ok(config) if !config.is_empty() => {
+                info!("Configuration found, starting");
+                if let Some(reply_tx) = reload_reply {
+                    let _ = reply_tx.send(ipc::Response::Ok);
+                }
+                return Ok(Some(config));
+            }
+            Ok(_) => ipc::Response::Error {
+                message: WAITING_FOR_CONFIG.to_string(),
+            },
+            Err(e) => {
+                let message = e.to_string();
+                if last_error.as_ref() != Some(&message) {
+                    warn!("Config not loadable yet: {}", message);
+                    last_error = Some(message.clone());
+                }
+                ipc::Response::Error {
+                    message: format!("Could not load config: {}", message),
+                }
+            }
+        };
+        if let Some(reply_tx) = reload_reply {
+            let _ = reply_tx.send(response);
         }
     }
+}
 
-    // Write our PID file
-    write_pid_file()?;
+async fn cmd_daemon(config: Config, dry_run: bool, sandboxed: bool) -> Result<()> {
+    // Hold the instance lock for as long as the daemon runs. It is released
+    // by the kernel on exit, however the process dies.
+    let _instance_lock = instance::InstanceLock::acquire()?;
+    info!(
+        "Daemon instance '{}' (PID {})",
+        instance::name(),
+        std::process::id()
+    );
 
     // Set up SIGTERM handler for graceful shutdown
     let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
         .map_err(|e| anyhow::anyhow!("Failed to register SIGTERM handler: {}", e))?;
 
-    let config = Config::load()?;
+    let notifier = systemd::Notifier::from_env();
+    let mut watchdog = systemd::watchdog_interval().map(tokio::time::interval);
 
-    if config.keyboards.is_empty() {
-        anyhow::bail!("No keyboards configured. Run 'kunai setup' first.");
+    // What `kunai watch` sees, from before there is a config to act on
+    let started_at = Instant::now();
+    let snapshot = tokio::sync::watch::Sender::new(ipc::Snapshot::default());
+
+    // Control socket for `kunai status`, `pause`, `resume` and `watch`
+    let (request_tx, mut request_rx) = mpsc::unbounded_channel::<ipc::Envelope>();
+    ipc::spawn_server(request_tx.clone(), snapshot.subscribe())?;
+
+    // The same requests, and the state, on the session bus for desktop widgets
+    let snapshots = snapshot.subscribe();
+    tokio::spawn(async move {
+        if let Err(e) = dbus::serve(request_tx, snapshots).await {
+            warn!("Not serving {} on the session bus: {}", dbus::bus_name(), e);
+        }
+    });
+
+    let Some(config) = wait_for_config(
+        config,
+        notifier.as_ref(),
+        &mut watchdog,
+        &mut sigterm,
+        &mut request_rx,
+        started_at,
+        dry_run,
+    )
+    .await?
+    else {
+        info!("Received SIGTERM while waiting for configuration");
+        return Ok(());
+    };
+    if let Some(reason) = restart_needed(Some(&config)) {
+        warn!("{}", reason);
     }
 
-    // Build configured device set for hotplug filtering
-    let configured_devices: HashSet<(u16, u16)> = config
-        .keyboards
-        .iter()
-        .filter_map(|kb| {
-            let vid = u16::from_str_radix(&kb.vendor_id, 16).ok()?;
-            let pid = u16::from_str_radix(&kb.product_id, 16).ok()?;
-            Some((vid, pid))
-        })
-        .collect();
+    // What the hotplug monitor reports; updated on reload
+    let hotplug_filter = Arc::new(RwLock::new(input::HotplugFilter::new(&config)));
 
-    // Build layout map
-    let mut layout_map = HashMap::new();
     for kb in &config.keyboards {
-        layout_map.insert(
-            format!("{}:{}", kb.vendor_id, kb.product_id),
-            (kb.name.clone(), kb.layout_index),
+        for language in kb.language_layouts.keys() {
+            if !config.languages.contains(language) {
+                warn!(
+                    "Keyboard {} maps unknown language {:?}; add it to `languages`",
+                    kb.name, language
+                );
+            }
+        }
+    }
+
+    // The helper reports typing at most once a second, not every press
+    if config.input_helper.is_some()
+        && (config.policy.min_keystrokes > 1 || config.policy.hysteresis_ms > 0)
+    {
+        warn!(
+            "With input_helper, key presses arrive at most once a second: min_keystrokes \
+             counts seconds of typing, and hysteresis_ms can be off by up to a second"
         );
     }
 
     // Channel for keyboard events (async)
-    let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+    let (event_tx, mut event_rx) = mpsc::channel(activity::CHANNEL_CAPACITY);
+
+    // One thread reads all keyboards
+    let activity = Arc::new(activity::Activity::default());
+    let (closed_tx, mut closed_rx) = mpsc::unbounded_channel();
+    let helper_event_tx = event_tx.clone();
+    let reactor = {
+        let activity = activity.clone();
+        reactor::Reactor::spawn(move |token, input: &KeyboardInput, readiness| {
+            monitor_keyboard(&activity, &event_tx, &closed_tx, token, input, readiness)
+        })?
+    };
 
     // Channel for hotplug signals (async) - bridged from sync rusb channel
     let (hotplug_async_tx, mut hotplug_async_rx) = mpsc::unbounded_channel::<()>();
 
+    // With an input helper, it reads the keyboards and follows hotplug
+    let (helper_tx, mut helper_rx) = mpsc::unbounded_channel();
+    if let Some(socket) = config.input_helper.clone() {
+        info!(
+            "Reading keyboards through the input helper at {}",
+            socket.display()
+        );
+        tokio::spawn(helper::follow(socket, helper_tx));
+    }
     // Start USB hotplug monitoring thread with async bridge
-    if rusb::has_hotplug() {
+    else if rusb::has_hotplug() {
         info!("Starting USB hotplug monitoring");
-        let configured = Arc::new(configured_devices);
+        let filter = hotplug_filter.clone();
 
         // Sync channel for rusb hotplug callbacks
         let (hotplug_sync_tx, hotplug_sync_rx) = std::sync::mpsc::channel();
 
         // Start the rusb hotplug monitor thread
         std::thread::spawn(move || {
-            if let Err(e) = run_hotplug_monitor(configured, hotplug_sync_tx) {
+            if let Err(e) = input::run_hotplug_monitor(filter, hotplug_sync_tx) {
                 error!("Hotplug monitor failed: {}", e);
             }
         });
@@ -504,17 +2072,116 @@ async fn cmd_daemon(dry_run: bool) -> Result<()> {
     }
 
     // Initialize daemon state
+    let script = match &config.script.path {
+        Some(path) => {
+            let script = script::RuleScript::load(
+                path,
+                Duration::from_millis(config.script.budget_ms),
+                config.script.record,
+            )?;
+            info!("Using rules script {}", path.display());
+            Some(script)
+        }
+        None => None,
+    };
+
+    // Layout switches run on their own thread; results come back here
+    let (switch_tx, mut switch_rx) = mpsc::unbounded_channel();
+    let switcher = if dry_run {
+        None
+    } else {
+        Some(switcher::Switcher::spawn(switch_tx)?)
+    };
+
     let mut state = DaemonState {
-        layout_map,
+        keyboards: keyboards_by_id(&config),
         monitored_keyboards: HashMap::new(),
+        default_layout: config.default_layout,
+        started_at,
+        dry_run,
+        paused: false,
+        resume_at: None,
+        last_switch: None,
+        sticky: config.sticky,
+        expected_layouts: VecDeque::new(),
+        policy: policy::SwitchPolicy::new(&config.policy, priorities(&config)),
+        languages: config.languages.clone(),
+        language: config.languages.first().cloned(),
+        app_rules: config.app_rules.clone(),
+        app_rule_mode: config.app_rule_mode,
+        remember_window_layouts: config.remember_window_layouts,
+        focused_window: None,
+        window_layouts: HashMap::new(),
+        current_layout: None,
+        workspace_rules: config.workspace_rules.clone(),
+        workspace_rule_mode: config.workspace_rule_mode,
+        focused_workspace: None,
+        rule_layout_applied: false,
+        script,
+        last_switch_at: None,
+        switcher,
+        switch_pending: None,
+        layouts_during_switch: VecDeque::new(),
+        activity,
+        devices: activity::Interner::default(),
+        reactor,
+        reopening: HashMap::new(),
+        sleeping: false,
+        session_active: true,
+        rescan_at: None,
+        hotplug_at: None,
+        helper_keyboards: config.input_helper.as_ref().map(|_| Vec::new()),
+        hooks: hooks::Hooks::spawn(&config.hooks, dry_run),
+        switch_hook: None,
+        layout_names: Vec::new(),
+        snapshot,
+        hotplug_filter,
     };
 
+    // Follow niri's event stream to know the current layout and notice
+    // manual switches and window/workspace focus changes
+    let (niri_tx, mut niri_rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        match niri::watch_events(niri_tx).await {
+            Ok(()) => warn!("niri event stream ended"),
+            Err(e) => warn!("niri event stream failed: {}", e),
+        }
+    });
+
+    // Follow logind to close keyboards around system sleep and while our
+    // session is in the background
+    let (logind_tx, mut logind_rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        match logind::watch_events(logind_tx).await {
+            Ok(()) => warn!("logind connection ended"),
+            Err(e) => warn!(
+                "Not following logind, sleep and session switches won't be handled: {}",
+                e
+            ),
+        }
+    });
+
     // Initial device enumeration
     info!("Performing initial keyboard enumeration");
-    manage_keyboard_monitors(&mut state, event_tx.clone()).await?;
+    if let Err(e) = manage_keyboard_monitors(&mut state) {
+        return exit_with_error(e);
+    }
 
     if state.monitored_keyboards.is_empty() {
-        anyhow::bail!("No configured keyboards found");
+        info!("No configured keyboards connected yet, waiting for one to be plugged in");
+    }
+
+    // Everything is set up; from here on only the syscalls in the allowlist
+    if sandboxed {
+        sandbox::restrict_syscalls()?;
+    }
+
+    // Tell systemd we're up once the first enumeration succeeded
+    if let Some(notifier) = &notifier {
+        notifier.ready(&format!(
+            "Monitoring {} keyboard(s)",
+            state.monitored_keyboards.len()
+        ));
     }
 
     if dry_run {
@@ -523,84 +2190,287 @@ async fn cmd_daemon(dry_run: bool) -> Result<()> {
         info!("Daemon started. Waiting for keyboard events...");
     }
 
-    let mut last_device = String::new();
-    let mut last_switch = Instant::now();
+    // Revert to the idle layout after a period without key presses
+    let idle = match (
+        config.idle_timeout_secs,
+        config.idle_layout.or(config.default_layout),
+    ) {
+        (Some(secs), Some(layout)) => Some((Duration::from_secs(secs), layout)),
+        (Some(_), None) => {
+            warn!("idle_timeout_secs is set but neither idle_layout nor default_layout is");
+            None
+        }
+        (None, _) => None,
+    };
+    let mut last_key = Instant::now();
+    let mut idle_reverted = false;
+    // Keyboard the input helper last reported as typed on
+    let mut helper_active: Option<DeviceIndex> = None;
+    let idle_deadline = |last_key: Instant, reverted: bool| {
+        // A timeout too long to represent never expires
+        idle.filter(|_| !reverted)
+            .and_then(|(timeout, _)| last_key.checked_add(timeout))
+    };
 
     // Main event loop
     loop {
+        state.publish_active();
+        state.publish_snapshot();
+
         tokio::select! {
-            // Keyboard event received
-            Some((device_id, target_layout)) = event_rx.recv() => {
-                // Debounce: only switch if different device
-                if device_id != last_device && last_switch.elapsed() > Duration::from_millis(100) {
-                    if dry_run {
-                        info!(
-                            "[DRY-RUN] Would switch to layout {} for device {}",
-                            target_layout, device_id
-                        );
-                    } else {
-                        if let Err(e) = niri::switch_to_layout(target_layout) {
-                            error!("Failed to switch layout: {}", e);
-                        } else {
-                            debug!("Switched to layout {} for device {}", target_layout, device_id);
-                            last_device = device_id;
-                            last_switch = Instant::now();
-                        }
-                    }
+            // Key presses on a keyboard other than the active one
+            Some(index) = event_rx.recv() => {
+                let (presses, at) = state.activity.take(state.devices.slot(index));
+                if presses == 0 {
+                    continue;
+                }
+                let device_id = state.devices.device_id(index).to_string();
+                last_key = at;
+                idle_reverted = false;
+
+                if state.paused {
+                    trace!("Paused, ignoring key press from {}", device_id);
+                    continue;
+                }
+
+                if let Some(active_at) = state.activity.active_last_key() {
+                    state.policy.active_key_at(active_at);
+                }
+                if !(0..presses).any(|_| state.policy.on_key(&device_id, at)) {
+                    continue;
+                }
+
+                let default = if state.rule_overrides() {
+                    debug!("A focus rule keeps the layout for {}", device_id);
+                    None
+                } else {
+                    state.target_layout(&device_id)
+                };
+                let Some(target_layout) = state.decide(Trigger::Keyboard(&device_id), default)
+                else {
+                    continue;
+                };
+                state.apply_layout(Trigger::Keyboard(&device_id), target_layout);
+            }
+
+            // The switch worker finished (or gave up on) a switch
+            Some(outcome) = switch_rx.recv() => state.on_switch_done(outcome),
+
+            // Layout or window focus changed in niri
+            Some(event) = niri_rx.recv() => match event {
+                niri::Event::LayoutSwitched(layout) => state.on_layout_switched(layout),
+                niri::Event::LayoutsChanged { names, current } => {
+                    state.layout_names = names;
+                    state.current_layout = Some(current);
+                }
+                niri::Event::WindowFocused(window) => state.on_window_focused(window),
+                niri::Event::WindowClosed(id) => {
+                    state.window_layouts.remove(&id);
+                }
+                niri::Event::WorkspaceFocused(workspace) => state.on_workspace_focused(workspace),
+            },
+
+            // No key presses for a while: go back to the idle layout
+            _ = sleep_until_deadline(idle_deadline(last_key, idle_reverted)) => {
+                // Presses on the active keyboard only show up in `activity`
+                let latest = state.activity.last_key();
+                if latest > last_key {
+                    last_key = latest;
+                    continue;
+                }
+                idle_reverted = true;
+                if let Some((timeout, layout)) = idle && !state.paused {
+                    info!(
+                        "No key presses for {}s, reverting to layout {}",
+                        timeout.as_secs(),
+                        layout
+                    );
+                    state.apply_layout(Trigger::Idle, layout);
+                    // The next key press, on any keyboard, switches again
+                    state.policy.reset();
                 }
             }
 
+            // Control request from the CLI
+            Some((request, reply_tx)) = request_rx.recv() => {
+                let response = state.handle_request(request);
+                let _ = reply_tx.send(response);
+            }
+
+            // Auto-resume after a timed pause
+            _ = sleep_until_deadline(state.resume_at) => {
+                state.resume();
+            }
+
             // USB device change detected (from async bridge)
             Some(()) = hotplug_async_rx.recv() => {
+                if state.input_suspended() {
+                    debug!("USB device change while keyboards are closed, rescanning later");
+                    continue;
+                }
                 info!("USB device change detected, waiting for device initialization...");
-                // Give kernel/udev time to fully initialize the input device
-                // Without this delay, the evdev EventStream may not receive events
-                // from a newly plugged device
-                tokio::time::sleep(Duration::from_millis(500)).await;
-                if let Err(e) = manage_keyboard_monitors(&mut state, event_tx.clone()).await {
-                    error!("Failed to re-enumerate devices: {}", e);
-                    if let Err(dump_err) = write_error_dump(&e) {
-                        error!("Failed to write error dump: {}", dump_err);
+                // Further changes push the rescan back until they settle
+                state.hotplug_at = Some(Instant::now() + HOTPLUG_SETTLE);
+            }
+
+            // Rescan once the changed devices have initialized
+            _ = sleep_until_deadline(state.hotplug_at) => {
+                state.hotplug_at = None;
+                let result = manage_keyboard_monitors(&mut state);
+                if let Some(notifier) = &notifier {
+                    notifier.status(&format!(
+                        "Monitoring {} keyboard(s)",
+                        state.monitored_keyboards.len()
+                    ));
+                }
+                match result {
+                    Ok(mut changes) => {
+                        state.settle_reopened(&mut changes);
+                        handle_monitor_changes(&mut state, &changes);
+                    }
+                    Err(e) => return exit_with_error(e),
+                }
+            }
+
+            // The system is going to sleep or has resumed, or our session
+            // moved to or from the foreground
+            Some(event) = logind_rx.recv() => match event {
+                logind::Event::PrepareForSleep(true, inhibitor) => {
+                    info!("System is going to sleep, closing keyboards");
+                    state.sleeping = true;
+                    state.rescan_at = None;
+                    state.quiesce_monitors();
+                    // Let the system go to sleep
+                    drop(inhibitor);
+                }
+                logind::Event::PrepareForSleep(false, _) => {
+                    info!("System resumed, rescanning keyboards");
+                    state.sleeping = false;
+                    if state.session_active {
+                        state.rescan_at = Some(Instant::now() + RESUME_SETTLE);
+                    }
+                }
+                logind::Event::SessionActive(false) if state.session_active => {
+                    info!("Session is in the background, closing keyboards");
+                    state.session_active = false;
+                    state.rescan_at = None;
+                    state.quiesce_monitors();
+                }
+                logind::Event::SessionActive(true) if !state.session_active => {
+                    info!("Session is active again, reopening keyboards");
+                    state.session_active = true;
+                    if !state.sleeping {
+                        state.rescan_at = Some(Instant::now());
+                    }
+                }
+                logind::Event::SessionActive(_) => {}
+            },
+
+            // Reopen every keyboard after resuming and restore the layout
+            _ = sleep_until_deadline(state.rescan_at) => {
+                state.rescan_at = None;
+                match manage_keyboard_monitors(&mut state) {
+                    Ok(mut changes) => state.settle_reopened(&mut changes),
+                    Err(e) => return exit_with_error(e),
+                }
+                state.resync();
+            }
+
+            // A keyboard's input stream ended
+            Some(index) = closed_rx.recv() => state.on_monitor_closed(index),
+
+            // The input helper's keyboards changed, or one of them was typed on
+            Some(message) = helper_rx.recv() => match message {
+                helper::Message::Keyboards { keyboards } => {
+                    state.helper_keyboards = Some(keyboards);
+                    if state.input_suspended() {
+                        continue;
+                    }
+                    match manage_keyboard_monitors(&mut state) {
+                        Ok(changes) => handle_monitor_changes(&mut state, &changes),
+                        Err(e) => return exit_with_error(e),
+                    }
+                }
+                helper::Message::Active { device_id } => {
+                    helper_active = state.devices.get(&device_id);
+                    state.helper_key_pressed(helper_active, &helper_event_tx);
+                }
+                helper::Message::Typing => {
+                    state.helper_key_pressed(helper_active, &helper_event_tx);
+                }
+            },
+
+            // Try to reopen keyboards whose input stream ended
+            _ = sleep_until_deadline(state.next_reopen()) => {
+                match manage_keyboard_monitors(&mut state) {
+                    Ok(mut changes) => {
+                        state.settle_reopened(&mut changes);
+                        handle_monitor_changes(&mut state, &changes);
                     }
-                    remove_pid_file();
-                    return Err(e);
+                    Err(e) => return exit_with_error(e),
+                }
+            }
+
+            // Keep the systemd watchdog fed while the loop is responsive
+            _ = tick_watchdog(&mut watchdog) => {
+                if let Some(notifier) = &notifier {
+                    notifier.watchdog();
                 }
             }
 
             // Graceful shutdown on SIGTERM
             _ = sigterm.recv() => {
                 info!("Received SIGTERM, shutting down gracefully");
-                remove_pid_file();
+                if let Some(notifier) = &notifier {
+                    notifier.stopping();
+                }
+                ipc::remove_socket();
+                ipc::remove_snapshot();
                 return Ok(());
             }
         }
     }
 }
 
-async fn monitor_keyboard(
-    device_id: String,
-    target_layout: u32,
-    mut stream: evdev::EventStream,
-    tx: mpsc::UnboundedSender<(String, u32)>,
+/// Record one key press, waking the main loop only if it matters.
+fn record_press(
+    activity: &activity::Activity,
+    tx: &mpsc::Sender<DeviceIndex>,
+    index: DeviceIndex,
+    slot: &activity::DeviceSlot,
 ) {
-    loop {
-        match stream.next_event().await {
-            Ok(event) if event.value() == 1 => {
-                // Key press detected, send to main loop
-                trace!("Key press from device {}", device_id);
-                let _ = tx.send((device_id.clone(), target_layout));
-            }
-            Ok(_) => {} // Ignore key releases
-            Err(e) => {
-                // Device disconnected or error
-                info!("Device {} stream ended: {}", device_id, e);
-                break;
+    if activity.key_pressed(index, slot) && tx.try_send(index).is_err() {
+        // Not queued after all, so let the next press try again
+        slot.clear();
+    }
+}
+
+/// Called on the input reactor thread for each keyboard's batch of events.
+fn monitor_keyboard(
+    activity: &activity::Activity,
+    tx: &mpsc::Sender<DeviceIndex>,
+    closed_tx: &mpsc::UnboundedSender<DeviceIndex>,
+    token: u64,
+    (device_id, slot): &KeyboardInput,
+    readiness: reactor::Readiness,
+) {
+    let index = token as DeviceIndex;
+    match readiness {
+        reactor::Readiness::KeyPresses(count) => {
+            trace!("{} key press(es) from device {}", count, device_id);
+            for _ in 0..count {
+                record_press(activity, tx, index, slot);
             }
         }
+        reactor::Readiness::Closed(e) => {
+            // Device disconnected or error; the main loop reopens it
+            info!("Device {} stream ended: {}", device_id, e);
+            let _ = closed_tx.send(index);
+        }
     }
 }
 
-async fn cmd_test() -> Result<()> {
+fn cmd_test() -> Result<()> {
     let keyboards = input::list_keyboards()?;
 
     if keyboards.is_empty() {
@@ -610,23 +2480,25 @@ async fn cmd_test() -> Result<()> {
 
     info!("Monitoring keyboards... (press Ctrl+C to stop)\n");
 
-    let (tx, mut rx) = mpsc::unbounded_channel();
-
-    // Spawn a task for each keyboard
-    for kb in keyboards {
-        let name = kb.name.clone();
-        let device = Device::open(&kb.device_path)?;
-        let stream = device.into_event_stream()?;
-        let tx = tx.clone();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let reactor = reactor::Reactor::spawn(move |_, name: &String, readiness| {
+        if let reactor::Readiness::KeyPresses(count) = readiness {
+            for _ in 0..count {
+                let _ = tx.send(name.clone());
+            }
+        }
+    })?;
 
-        tokio::spawn(async move {
-            test_keyboard(name, stream, tx).await;
-        });
+    // Read every keyboard from the same reactor
+    for (token, kb) in keyboards.into_iter().enumerate() {
+        reactor.add(
+            token as u64,
+            reactor::open_device(&kb.device_path)?,
+            kb.name,
+        )?;
     }
 
-    drop(tx);
-
-    while let Some(name) = rx.recv().await {
+    while let Ok(name) = rx.recv() {
         let now = chrono::Local::now();
         let text = format!("[{}] Event from: {}", now.format("%H:%M:%S"), name);
         info!(text)
@@ -635,18 +2507,16 @@ async fn cmd_test() -> Result<()> {
     Ok(())
 }
 
-async fn test_keyboard(
-    name: String,
-    mut stream: evdev::EventStream,
-    tx: mpsc::UnboundedSender<String>,
-) {
-    loop {
-        match stream.next_event().await {
-            Ok(event) if event.value() == 1 => {
-                let _ = tx.send(name.clone());
-            }
-            Ok(_) => {}
-            Err(_) => break,
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pause_deadline_refuses_huge_timeouts() {
+        let now = Instant::now();
+        assert_eq!(pause_deadline(now, 60), Ok(now + Duration::from_secs(60)));
+        assert!(pause_deadline(now, MAX_PAUSE_SECS).is_ok());
+        assert!(pause_deadline(now, MAX_PAUSE_SECS + 1).is_err());
+        assert!(pause_deadline(now, u64::MAX).is_err());
     }
 }
@@ -1,10 +1,65 @@
-use std::process::Command;
+use std::{
+    collections::HashMap,
+    process::{
+        Command,
+        Stdio,
+    },
+};
 
 use anyhow::{
     Result,
     anyhow,
 };
+use serde::Deserialize;
 use serde_json::Value;
+use tokio::{
+    io::{
+        AsyncBufReadExt,
+        BufReader,
+    },
+    sync::mpsc,
+};
+use tracing::{
+    debug,
+    warn,
+};
+
+/// Events from niri's event stream that the daemon cares about
+#[derive(Debug)]
+pub enum Event {
+    /// The active layout changed, by us or by the user
+    LayoutSwitched(u32),
+    /// niri's layouts, sent when the stream starts and when its config is
+    /// reloaded
+    LayoutsChanged { names: Vec<String>, current: u32 },
+    /// Keyboard focus moved to another window (or to none), or the focused
+    /// window's title/app_id changed
+    WindowFocused(Option<Window>),
+    /// A window was closed
+    WindowClosed(u64),
+    /// Another workspace (possibly on another output) got focus
+    WorkspaceFocused(Option<Workspace>),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Workspace {
+    pub id:         u64,
+    /// Index on its output, starting at 1
+    pub idx:        u8,
+    pub name:       Option<String>,
+    pub output:     Option<String>,
+    #[serde(default)]
+    pub is_focused: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Window {
+    pub id:         u64,
+    pub app_id:     Option<String>,
+    pub title:      Option<String>,
+    #[serde(default)]
+    pub is_focused: bool,
+}
 
 /// Get available keyboard layouts from niri
 pub fn get_layouts() -> Result<Vec<String>> {
@@ -34,10 +89,13 @@ pub fn get_current_index() -> Result<u32> {
 }
 
 /// Switch to target layout (cycles with keyboard-layout-next)
-pub fn switch_to_layout(target: u32) -> Result<()> {
+///
+/// Returns the layout indices niri passes through, in order, so the resulting
+/// `LayoutSwitched` events can be told apart from manual switches.
+pub fn switch_to_layout(target: u32) -> Result<Vec<u32>> {
     let current = get_current_index()?;
     if current == target {
-        return Ok(());
+        return Ok(vec![]);
     }
 
     let layouts = get_layouts()?;
@@ -46,11 +104,134 @@ pub fn switch_to_layout(target: u32) -> Result<()> {
     // Calculate shortest path (forward wrapping)
     let steps = (target + total - current) % total;
 
-    for _ in 0..steps {
+    let mut visited = Vec::with_capacity(steps as usize);
+    for step in 1..=steps {
         Command::new("niri")
             .args(["msg", "action", "switch-layout", "next"])
             .output()?;
+        visited.push((current + step) % total);
+    }
+
+    Ok(visited)
+}
+
+/// Follow `niri msg --json event-stream` and forward relevant events until
+/// the stream ends.
+pub async fn watch_events(tx: mpsc::UnboundedSender<Event>) -> Result<()> {
+    let mut child = tokio::process::Command::new("niri")
+        .args(["msg", "--json", "event-stream"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| anyhow!("niri event stream has no stdout"))?;
+    let mut lines = BufReader::new(stdout).lines();
+
+    // niri only sends ids on focus changes, so keep track of windows and
+    // workspaces
+    let mut windows: HashMap<u64, Window> = HashMap::new();
+    let mut workspaces: HashMap<u64, Workspace> = HashMap::new();
+
+    while let Some(line) = lines.next_line().await? {
+        let json: Value = match serde_json::from_str(&line) {
+            Ok(json) => json,
+            Err(e) => {
+                warn!("Invalid niri event: {}", e);
+                continue;
+            }
+        };
+
+        if let Some(event) = parse_event(&json, &mut windows, &mut workspaces) {
+            debug!("niri event: {:?}", event);
+            if tx.send(event).is_err() {
+                break;
+            }
+        }
     }
 
     Ok(())
 }
+
+/// Turn one line of niri's event stream into an `Event`, updating the window
+/// and workspace tables along the way.
+fn parse_event(
+    json: &Value,
+    windows: &mut HashMap<u64, Window>,
+    workspaces: &mut HashMap<u64, Workspace>,
+) -> Option<Event> {
+    if let Some(idx) = json["KeyboardLayoutSwitched"]["idx"].as_u64() {
+        return Some(Event::LayoutSwitched(idx as u32));
+    }
+
+    if let Some(layouts) = json["KeyboardLayoutsChanged"].get("keyboard_layouts") {
+        let names = layouts["names"]
+            .as_array()?
+            .iter()
+            .filter_map(|v| v.as_str().map(String::from))
+            .collect();
+        let current = layouts["current_idx"].as_u64().unwrap_or(0) as u32;
+        return Some(Event::LayoutsChanged { names, current });
+    }
+
+    if let Some(list) = json.get("WindowsChanged") {
+        let list: Vec<Window> = serde_json::from_value(list["windows"].clone()).ok()?;
+        windows.clear();
+        windows.extend(list.into_iter().map(|w| (w.id, w)));
+        let focused = windows.values().find(|w| w.is_focused).cloned();
+        return Some(Event::WindowFocused(focused));
+    }
+
+    if let Some(changed) = json.get("WindowOpenedOrChanged") {
+        let window: Window = serde_json::from_value(changed["window"].clone()).ok()?;
+        if window.is_focused {
+            for other in windows.values_mut() {
+                other.is_focused = false;
+            }
+        }
+        windows.insert(window.id, window.clone());
+        return window
+            .is_focused
+            .then_some(Event::WindowFocused(Some(window)));
+    }
+
+    if let Some(closed) = json.get("WindowClosed") {
+        let id = closed["id"].as_u64()?;
+        windows.remove(&id);
+        return Some(Event::WindowClosed(id));
+    }
+
+    if let Some(list) = json.get("WorkspacesChanged") {
+        let list: Vec<Workspace> = serde_json::from_value(list["workspaces"].clone()).ok()?;
+        workspaces.clear();
+        workspaces.extend(list.into_iter().map(|w| (w.id, w)));
+        let focused = workspaces.values().find(|w| w.is_focused).cloned();
+        return Some(Event::WorkspaceFocused(focused));
+    }
+
+    if let Some(activated) = json.get("WorkspaceActivated") {
+        if !activated["focused"].as_bool().unwrap_or(false) {
+            return None;
+        }
+        let id = activated["id"].as_u64()?;
+        for workspace in workspaces.values_mut() {
+            workspace.is_focused = workspace.id == id;
+        }
+        return Some(Event::WorkspaceFocused(workspaces.get(&id).cloned()));
+    }
+
+    if let Some(focus) = json.get("WindowFocusChanged") {
+        let id = focus["id"].as_u64();
+        for window in windows.values_mut() {
+            window.is_focused = Some(window.id) == id;
+        }
+        return Some(Event::WindowFocused(
+            id.and_then(|id| windows.get(&id).cloned()),
+        ));
+    }
+
+    None
+}
@@ -0,0 +1,334 @@
+use std::{
+    collections::HashMap,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use crate::config::PolicyConfig;
+
+/// Decides, key press by key press, when the active keyboard changes.
+///
+/// The policy is purely a function of the key press timeline it is fed, with
+/// the time of each press passed in explicitly, so the daemon and dry-run go
+/// through exactly the same decisions.
+pub struct SwitchPolicy {
+    debounce:       Duration,
+    hysteresis:     Duration,
+    min_keystrokes: u32,
+    priorities:     HashMap<String, i32>, // "vid:pid" -> priority
+
+    active:          Option<String>,
+    previous:        Option<String>,
+    active_last_key: Option<Instant>,
+    last_switch:     Option<Instant>,
+    candidate:       Option<(String, u32, Instant)>, // device, consecutive presses, last press
+}
+
+impl SwitchPolicy {
+    pub fn new(config: &PolicyConfig, priorities: HashMap<String, i32>) -> Self {
+        Self {
+            debounce: Duration::from_millis(config.debounce_ms),
+            hysteresis: Duration::from_millis(config.hysteresis_ms),
+            min_keystrokes: config.min_keystrokes.max(1),
+            priorities,
+            active: None,
+            previous: None,
+            active_last_key: None,
+            last_switch: None,
+            candidate: None,
+        }
+    }
+
+    /// The keyboard whose layout is currently applied, if any.
+    pub fn active(&self) -> Option<&str> {
+        self.active.as_deref()
+    }
+
+    /// The keyboard that was active before the current one, if any.
+    pub fn previous(&self) -> Option<&str> {
+        self.previous.as_deref()
+    }
+
+    fn priority(&self, device_id: &str) -> i32 {
+        self.priorities.get(device_id).copied().unwrap_or(0)
+    }
+
+    /// Feed a key press from `device_id` at time `now`. Returns true if the
+    /// layout should now be switched to that keyboard's layout.
+    pub fn on_key(&mut self, device_id: &str, now: Instant) -> bool {
+        if self.active.as_deref() == Some(device_id) {
+            self.active_last_key = Some(now);
+            self.candidate = None;
+            return false;
+        }
+
+        // Count consecutive presses on the keyboard that wants to take over
+        let count = match &mut self.candidate {
+            Some((id, count, last)) if id == device_id => {
+                *count += 1;
+                *last = now;
+                *count
+            }
+            _ => {
+                self.candidate = Some((device_id.to_string(), 1, now));
+                1
+            }
+        };
+
+        if self
+            .last_switch
+            .is_some_and(|at| now.duration_since(at) < self.debounce)
+        {
+            return false;
+        }
+
+        let Some(active) = self.active.as_deref() else {
+            // Nothing applied yet, take the first keyboard that types
+            self.commit(device_id, now);
+            return true;
+        };
+
+        if count < self.min_keystrokes {
+            return false;
+        }
+
+        // A higher-priority keyboard doesn't wait out the hysteresis window
+        if self.priority(device_id) > self.priority(active) {
+            self.commit(device_id, now);
+            return true;
+        }
+
+        if self
+            .active_last_key
+            .is_some_and(|at| now.duration_since(at) < self.hysteresis)
+        {
+            return false;
+        }
+
+        self.commit(device_id, now);
+        true
+    }
+
+    /// The active keyboard was last pressed at `at`. Presses on the active
+    /// keyboard aren't fed through `on_key` one by one, so this keeps the
+    /// hysteresis window current and breaks another keyboard's streak.
+    pub fn active_key_at(&mut self, at: Instant) {
+        if self.active.is_none() || self.active_last_key.is_some_and(|last| last >= at) {
+            return;
+        }
+        self.active_last_key = Some(at);
+        if self
+            .candidate
+            .as_ref()
+            .is_some_and(|(_, _, last)| *last < at)
+        {
+            self.candidate = None;
+        }
+    }
+
+    /// Make `device_id` the active keyboard regardless of the rules, e.g.
+    /// when it was just plugged in.
+    pub fn activate(&mut self, device_id: &str, now: Instant) {
+        if self.active.as_deref() != Some(device_id) {
+            self.commit(device_id, now);
+        }
+    }
+
+    fn commit(&mut self, device_id: &str, now: Instant) {
+        self.previous = self.active.replace(device_id.to_string());
+        self.active_last_key = Some(now);
+        self.last_switch = Some(now);
+        self.candidate = None;
+    }
+
+    /// Forget the active keyboard, e.g. after the layout was changed from
+    /// outside the policy, so the next key press on any keyboard switches.
+    pub fn reset(&mut self) {
+        self.active = None;
+        self.previous = None;
+        self.active_last_key = None;
+        self.candidate = None;
+    }
+
+    /// The switch requested by the last `on_key` could not be applied. Go
+    /// back to the previous keyboard; the debounce window still applies, so a
+    /// failing backend is retried at most once per window, not on every key.
+    pub fn switch_failed(&mut self) {
+        self.active = self.previous.take();
+        self.active_last_key = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: &str = "aaaa:0001";
+    const B: &str = "bbbb:0002";
+
+    fn policy(debounce_ms: u64, hysteresis_ms: u64, min_keystrokes: u32) -> SwitchPolicy {
+        let config = PolicyConfig {
+            debounce_ms,
+            hysteresis_ms,
+            min_keystrokes,
+        };
+        SwitchPolicy::new(&config, HashMap::new())
+    }
+
+    /// `ms` milliseconds after `start`
+    fn at(start: Instant, ms: u64) -> Instant {
+        start + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn first_keyboard_to_type_becomes_active() {
+        let t = Instant::now();
+        let mut policy = policy(100, 0, 3);
+        assert!(policy.on_key(A, t));
+        assert_eq!(policy.active(), Some(A));
+        assert_eq!(policy.previous(), None);
+        assert!(!policy.on_key(A, at(t, 10)));
+    }
+
+    #[test]
+    fn debounce_limits_switch_rate() {
+        let t = Instant::now();
+        let mut policy = policy(100, 0, 1);
+        policy.on_key(A, t);
+        assert!(!policy.on_key(B, at(t, 50)));
+        assert_eq!(policy.active(), Some(A));
+        assert!(policy.on_key(B, at(t, 150)));
+        assert_eq!(policy.active(), Some(B));
+        assert_eq!(policy.previous(), Some(A));
+    }
+
+    #[test]
+    fn hysteresis_counts_from_the_active_keyboards_last_press() {
+        let t = Instant::now();
+        let mut policy = policy(0, 500, 1);
+        policy.on_key(A, t);
+        policy.on_key(A, at(t, 100));
+        assert!(!policy.on_key(B, at(t, 400)));
+        assert!(!policy.on_key(B, at(t, 599)));
+        assert!(policy.on_key(B, at(t, 600)));
+    }
+
+    #[test]
+    fn min_keystrokes_needs_a_streak() {
+        let t = Instant::now();
+        let mut policy = policy(0, 0, 3);
+        policy.on_key(A, t);
+        assert!(!policy.on_key(B, at(t, 10)));
+        assert!(!policy.on_key(B, at(t, 20)));
+        assert!(policy.on_key(B, at(t, 30)));
+    }
+
+    #[test]
+    fn typing_on_the_active_keyboard_breaks_the_streak() {
+        let t = Instant::now();
+        let mut policy = policy(0, 0, 3);
+        policy.on_key(A, t);
+        policy.on_key(B, at(t, 10));
+        policy.on_key(B, at(t, 20));
+        policy.on_key(A, at(t, 25));
+        assert!(!policy.on_key(B, at(t, 30)));
+        assert!(!policy.on_key(B, at(t, 40)));
+        assert!(policy.on_key(B, at(t, 50)));
+    }
+
+    #[test]
+    fn active_key_at_breaks_only_older_streaks() {
+        let t = Instant::now();
+        let mut policy = policy(0, 0, 3);
+        policy.on_key(A, t);
+
+        // Reported after B's presses started, but pressed before them
+        policy.on_key(B, at(t, 10));
+        policy.active_key_at(at(t, 5));
+        policy.on_key(B, at(t, 20));
+        assert!(policy.on_key(B, at(t, 30)));
+
+        // B is active now; a press on it after A's streak began breaks it
+        policy.on_key(A, at(t, 40));
+        policy.on_key(A, at(t, 50));
+        policy.active_key_at(at(t, 55));
+        assert!(!policy.on_key(A, at(t, 60)));
+        assert!(!policy.on_key(A, at(t, 70)));
+        assert!(policy.on_key(A, at(t, 80)));
+    }
+
+    #[test]
+    fn higher_priority_preempts() {
+        let t = Instant::now();
+        let config = PolicyConfig {
+            debounce_ms:    100,
+            hysteresis_ms:  1000,
+            min_keystrokes: 3,
+        };
+        let mut policy = SwitchPolicy::new(&config, HashMap::from([(B.to_string(), 1)]));
+        policy.on_key(A, t);
+
+        // Still not within the debounce window
+        assert!(!policy.on_key(B, at(t, 50)));
+        assert!(!policy.on_key(B, at(t, 150)));
+        assert!(policy.on_key(B, at(t, 200)));
+
+        // The lower-priority keyboard has to wait like any other
+        assert!(!policy.on_key(A, at(t, 400)));
+        assert!(!policy.on_key(A, at(t, 500)));
+        assert!(!policy.on_key(A, at(t, 1100)));
+        assert!(policy.on_key(A, at(t, 1200)));
+    }
+
+    #[test]
+    fn higher_priority_still_needs_min_keystrokes() {
+        let t = Instant::now();
+        let config = PolicyConfig {
+            debounce_ms:    0,
+            hysteresis_ms:  1000,
+            min_keystrokes: 2,
+        };
+        let mut policy = SwitchPolicy::new(&config, HashMap::from([(B.to_string(), 1)]));
+        policy.on_key(A, t);
+
+        // A stray press doesn't take over, and typing on A breaks the streak
+        assert!(!policy.on_key(B, at(t, 100)));
+        policy.active_key_at(at(t, 150));
+        assert!(!policy.on_key(B, at(t, 200)));
+        assert!(policy.on_key(B, at(t, 250)));
+    }
+
+    #[test]
+    fn switch_failed_restores_the_previous_keyboard() {
+        let t = Instant::now();
+        let mut policy = policy(100, 0, 1);
+        policy.on_key(A, t);
+        assert!(policy.on_key(B, at(t, 200)));
+        policy.switch_failed();
+        assert_eq!(policy.active(), Some(A));
+        assert_eq!(policy.previous(), None);
+
+        // Retried once the debounce window has passed
+        assert!(!policy.on_key(B, at(t, 250)));
+        assert!(policy.on_key(B, at(t, 300)));
+        assert_eq!(policy.active(), Some(B));
+    }
+
+    #[test]
+    fn reset_forgets_the_active_keyboard() {
+        let t = Instant::now();
+        let mut policy = policy(100, 1000, 3);
+        policy.on_key(A, t);
+        policy.on_key(B, at(t, 10));
+        policy.reset();
+        assert_eq!(policy.active(), None);
+        assert_eq!(policy.previous(), None);
+
+        // Debounced still, but no hysteresis or streak needed
+        assert!(!policy.on_key(B, at(t, 50)));
+        assert!(policy.on_key(B, at(t, 200)));
+        assert_eq!(policy.previous(), None);
+    }
+}
@@ -0,0 +1,212 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::Path,
+    sync::{
+        Arc,
+        mpsc,
+    },
+    time::SystemTime,
+};
+
+use anyhow::Result;
+use evdev::{
+    Device,
+    EventType,
+    InputEvent,
+};
+use nix::{
+    errno::Errno,
+    sys::{
+        epoll::{
+            Epoll,
+            EpollCreateFlags,
+            EpollEvent,
+            EpollFlags,
+            EpollTimeout,
+        },
+        eventfd::{
+            EfdFlags,
+            EventFd,
+        },
+    },
+};
+use tracing::{
+    debug,
+    error,
+};
+
+/// epoll token of the eventfd used to wake the reactor for new commands
+const WAKE_TOKEN: u64 = u64::MAX;
+
+/// What the reactor reports for a device
+pub enum Readiness {
+    /// Key presses read since the last report, in whole SYN_REPORT batches
+    KeyPresses(u32),
+    /// Reading failed (e.g. the device was unplugged); it has been removed
+    Closed(io::Error),
+}
+
+enum Command<T> {
+    Add(u64, Box<Device>, T),
+    Remove(u64),
+}
+
+/// Reads every input device from one epoll loop on a dedicated thread.
+///
+/// Devices are added and removed by token as they come and go, and `T` is
+/// whatever the handler needs to know about each one. When several devices
+/// are ready at once, their batches are handed to the handler in the order
+/// their first key press happened.
+pub struct Reactor<T> {
+    commands: mpsc::Sender<Command<T>>,
+    wake:     Arc<EventFd>,
+}
+
+impl<T: Send + 'static> Reactor<T> {
+    pub fn spawn(handler: impl FnMut(u64, &T, Readiness) + Send + 'static) -> Result<Self> {
+        let epoll = Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC)?;
+        let wake = Arc::new(EventFd::from_flags(
+            EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK,
+        )?);
+        epoll.add(
+            wake.as_ref(),
+            EpollEvent::new(EpollFlags::EPOLLIN, WAKE_TOKEN),
+        )?;
+
+        let (commands, command_rx) = mpsc::channel();
+        let thread_wake = wake.clone();
+        std::thread::Builder::new()
+            .name("kunai-input".to_string())
+            .spawn(move || {
+                if let Err(e) = run(&epoll, &thread_wake, &command_rx, handler) {
+                    error!("Input reactor failed: {}", e);
+                }
+            })?;
+
+        Ok(Self { commands, wake })
+    }
+
+    fn send(&self, command: Command<T>) -> Result<()> {
+        self.commands
+            .send(command)
+            .map_err(|_| anyhow::anyhow!("Input reactor has stopped"))?;
+        self.wake.write(1)?;
+        Ok(())
+    }
+
+    /// Start reading `device`, reporting its events under `token`. The
+    /// device must be non-blocking. Fails only if the reactor has stopped; a
+    /// device it cannot watch is reported as `Readiness::Closed`.
+    pub fn add(&self, token: u64, device: Device, data: T) -> Result<()> {
+        self.send(Command::Add(token, Box::new(device), data))
+    }
+
+    /// Stop reading the device registered under `token`, if any.
+    pub fn remove(&self, token: u64) -> Result<()> {
+        self.send(Command::Remove(token))
+    }
+}
+
+/// Open the input device at `path` for `Reactor::add`.
+pub fn open_device(path: &Path) -> io::Result<Device> {
+    let device = Device::open(path)?;
+    device.set_nonblocking(true)?;
+    Ok(device)
+}
+
+/// The events the daemon reacts to: key presses, not releases or repeats.
+fn is_key_press(event: &InputEvent) -> bool {
+    event.event_type() == EventType::KEY && event.value() == 1
+}
+
+/// Drain everything `device` has buffered. Returns the number of key presses
+/// and when the first of them happened.
+fn read_presses(device: &mut Device) -> io::Result<(u32, Option<SystemTime>)> {
+    let mut count = 0;
+    let mut first = None;
+    loop {
+        match device.fetch_events() {
+            Ok(events) => {
+                for event in events.filter(is_key_press) {
+                    first.get_or_insert(event.timestamp());
+                    count += 1;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok((count, first)),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn run<T>(
+    epoll: &Epoll,
+    wake: &EventFd,
+    commands: &mpsc::Receiver<Command<T>>,
+    mut handler: impl FnMut(u64, &T, Readiness),
+) -> Result<()> {
+    let mut devices: HashMap<u64, (Box<Device>, T)> = HashMap::new();
+    let mut events = [EpollEvent::empty(); 32];
+
+    loop {
+        let ready = match epoll.wait(&mut events, EpollTimeout::NONE) {
+            Ok(ready) => ready,
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut batches = Vec::new();
+        for event in &events[..ready] {
+            let token = event.data();
+
+            if token == WAKE_TOKEN {
+                let _ = wake.read();
+                loop {
+                    match commands.try_recv() {
+                        Ok(Command::Add(token, device, data)) => {
+                            match epoll.add(&device, EpollEvent::new(EpollFlags::EPOLLIN, token)) {
+                                Ok(()) => {
+                                    devices.insert(token, (device, data));
+                                }
+                                Err(e) => handler(token, &data, Readiness::Closed(e.into())),
+                            }
+                        }
+                        Ok(Command::Remove(token)) => {
+                            if let Some((device, _)) = devices.remove(&token) {
+                                let _ = epoll.delete(&device);
+                            }
+                        }
+                        Err(mpsc::TryRecvError::Empty) => break,
+                        Err(mpsc::TryRecvError::Disconnected) => {
+                            debug!("Input reactor shutting down");
+                            return Ok(());
+                        }
+                    }
+                }
+                continue;
+            }
+
+            let Some((device, _)) = devices.get_mut(&token) else {
+                continue;
+            };
+            match read_presses(device) {
+                Ok((0, _)) => {}
+                Ok((count, first)) => batches.push((first, token, count)),
+                Err(e) => {
+                    if let Some((device, data)) = devices.remove(&token) {
+                        let _ = epoll.delete(&device);
+                        handler(token, &data, Readiness::Closed(e));
+                    }
+                }
+            }
+        }
+
+        // Keyboards typed on at the same time are reported in press order
+        batches.sort_by_key(|(first, token, _)| (*first, *token));
+        for (_, token, count) in batches {
+            if let Some((_, data)) = devices.get(&token) {
+                handler(token, data, Readiness::KeyPresses(count));
+            }
+        }
+    }
+}
@@ -0,0 +1,518 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io,
+    path::PathBuf,
+    process::Command,
+    sync::OnceLock,
+};
+
+use anyhow::Result;
+use landlock::{
+    ABI,
+    Access,
+    AccessFs,
+    LandlockStatus,
+    RestrictionStatus,
+    Ruleset,
+    RulesetAttr,
+    RulesetCreatedAttr,
+    RulesetStatus,
+    path_beneath_rules,
+};
+use nix::libc;
+use seccompiler::{
+    SeccompAction,
+    SeccompCmpArgLen,
+    SeccompCmpOp,
+    SeccompCondition,
+    SeccompFilter,
+    SeccompRule,
+};
+use tracing::{
+    info,
+    warn,
+};
+
+use crate::{
+    config::{
+        self,
+        Config,
+    },
+    instance,
+};
+
+/// Newest Landlock ABI we ask for; older kernels get what they support
+const LANDLOCK_ABI: ABI = ABI::V5;
+
+/// System directories programs (`niri msg`, hooks) are run from
+const SYSTEM_DIRS: &[&str] = &[
+    "/usr",
+    "/bin",
+    "/sbin",
+    "/lib",
+    "/lib64",
+    "/nix/store",
+    "/run/current-system",
+];
+
+/// Read-only paths: name service and time zone data, and what libusb (USB
+/// hotplug) and the script's `process_running` look at
+const READ_ONLY: &[&str] = &["/etc", "/proc", "/sys", "/dev/bus/usb", "/run/udev"];
+
+/// Syscalls the daemon, its runtime and the programs it runs need after
+/// startup. Anything else fails with EPERM.
+const SYSCALLS: &[i64] = &[
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_close,
+    libc::SYS_close_range,
+    libc::SYS_openat,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_statfs,
+    libc::SYS_fstatfs,
+    libc::SYS_lseek,
+    libc::SYS_getdents64,
+    libc::SYS_readlinkat,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_getcwd,
+    libc::SYS_mkdirat,
+    libc::SYS_unlinkat,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_ftruncate,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_fchmod,
+    libc::SYS_utimensat,
+    libc::SYS_flock,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_pipe2,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mprotect,
+    libc::SYS_mremap,
+    libc::SYS_madvise,
+    libc::SYS_brk,
+    libc::SYS_membarrier,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_restart_syscall,
+    libc::SYS_kill,
+    libc::SYS_tgkill,
+    libc::SYS_tkill,
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_epoll_pwait2,
+    libc::SYS_eventfd2,
+    libc::SYS_timerfd_create,
+    libc::SYS_timerfd_settime,
+    libc::SYS_timerfd_gettime,
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+    libc::SYS_futex,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_nanosleep,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_gettimeofday,
+    libc::SYS_getrandom,
+    libc::SYS_connect,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_accept4,
+    libc::SYS_sendto,
+    libc::SYS_sendmsg,
+    libc::SYS_recvfrom,
+    libc::SYS_recvmsg,
+    libc::SYS_shutdown,
+    libc::SYS_getsockopt,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_socketpair,
+    libc::SYS_getpid,
+    libc::SYS_getppid,
+    libc::SYS_gettid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_getresuid,
+    libc::SYS_getresgid,
+    libc::SYS_getgroups,
+    libc::SYS_getpgid,
+    libc::SYS_setpgid,
+    libc::SYS_setsid,
+    libc::SYS_umask,
+    libc::SYS_uname,
+    libc::SYS_sysinfo,
+    libc::SYS_prctl,
+    libc::SYS_prlimit64,
+    libc::SYS_set_tid_address,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_execve,
+    libc::SYS_wait4,
+    libc::SYS_waitid,
+    libc::SYS_pidfd_open,
+    libc::SYS_pidfd_send_signal,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_arch_prctl,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_lstat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_access,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_readlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_unlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rename,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_mkdir,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_dup2,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_pipe,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_select,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_getdents,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_vfork,
+];
+
+/// Socket families the daemon uses: Unix sockets for niri, D-Bus and the
+/// control socket, netlink for USB hotplug. No network access.
+const SOCKET_FAMILIES: &[libc::c_int] = &[libc::AF_UNIX, libc::AF_NETLINK];
+
+/// Directories and files the Landlock ruleset lets the daemon read, set once
+/// it is enforced
+static READABLE: OnceLock<Vec<PathBuf>> = OnceLock::new();
+
+fn config_dir() -> Result<PathBuf> {
+    Ok(dirs::config_dir()
+        .ok_or_else(|| anyhow::anyhow!("Could not find config directory"))?
+        .join("kunai"))
+}
+
+/// Restrict the filesystem to what the daemon needs, with Landlock. Only
+/// applies to the calling thread and threads it creates later, so this has
+/// to run before the tokio runtime starts. Also sets `no_new_privs`.
+pub fn restrict_filesystem(config: &Config) -> Result<()> {
+    let writable = [config_dir()?, instance::runtime_dir()?];
+    let status = landlock(config, &writable)?;
+
+    if status.ruleset != RulesetStatus::NotEnforced {
+        let readable = exec_dirs()
+            .into_iter()
+            .chain(read_only(config))
+            .chain([PathBuf::from("/dev/input")])
+            .chain(writable)
+            .map(|path| fs::canonicalize(&path).unwrap_or(path))
+            .collect();
+        let _ = READABLE.set(readable);
+    }
+
+    match (status.ruleset, status.landlock) {
+        (RulesetStatus::FullyEnforced, _) => info!("Sandbox: Landlock filesystem rules enforced"),
+        (RulesetStatus::PartiallyEnforced, LandlockStatus::Available { effective_abi, .. }) => {
+            warn!(
+                "Sandbox: Landlock only partially enforced (kernel supports ABI {:?})",
+                effective_abi
+            )
+        }
+        (RulesetStatus::PartiallyEnforced, _) => {
+            warn!("Sandbox: Landlock only partially enforced")
+        }
+        (RulesetStatus::NotEnforced, LandlockStatus::NotEnabled) => warn!(
+            "Sandbox: Landlock is built into this kernel but not enabled (add it to the lsm= \
+             boot parameter); filesystem access is NOT restricted"
+        ),
+        (RulesetStatus::NotEnforced, _) => warn!(
+            "Sandbox: this kernel does not support Landlock (needs Linux 5.13+); filesystem \
+             access is NOT restricted"
+        ),
+    }
+    if !status.no_new_privs {
+        warn!("Sandbox: could not set no_new_privs");
+    }
+
+    Ok(())
+}
+
+/// Those of `paths` the Landlock ruleset applied at startup doesn't let the
+/// daemon read, e.g. a rules script added to the config since. Empty when
+/// the filesystem isn't restricted.
+pub fn outside_ruleset(paths: impl IntoIterator<Item = PathBuf>) -> Vec<PathBuf> {
+    let Some(readable) = READABLE.get() else {
+        return Vec::new();
+    };
+    paths
+        .into_iter()
+        .map(|path| fs::canonicalize(&path).unwrap_or(path))
+        .filter(|path| !readable.iter().any(|root| path.starts_with(root)))
+        .collect()
+}
+
+/// Directories programs may be run from: the system ones and `$PATH`
+fn exec_dirs() -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = SYSTEM_DIRS.iter().map(PathBuf::from).collect();
+    if let Some(path) = std::env::var_os("PATH") {
+        dirs.extend(std::env::split_paths(&path));
+    }
+    dirs
+}
+
+/// Read-only paths, including the rules script and the config file, which
+/// may be a symlink out of the config directory
+fn read_only(config: &Config) -> Vec<PathBuf> {
+    READ_ONLY
+        .iter()
+        .map(PathBuf::from)
+        .chain(config::get_config_path().ok())
+        .chain(config.script.path.clone())
+        .map(|path| fs::canonicalize(&path).unwrap_or(path))
+        .collect()
+}
+
+/// Apply the daemon's Landlock ruleset to the calling thread, with `writable`
+/// as the only writable directories.
+fn landlock(config: &Config, writable: &[PathBuf]) -> Result<RestrictionStatus> {
+    let abi = LANDLOCK_ABI;
+    let read = AccessFs::ReadFile | AccessFs::ReadDir;
+    let read_exec = AccessFs::from_read(abi);
+    let read_write = AccessFs::from_all(abi) & !AccessFs::Execute;
+
+    Ok(Ruleset::default()
+        .handle_access(AccessFs::from_all(abi))?
+        .create()?
+        .add_rules(path_beneath_rules(exec_dirs(), read_exec))?
+        .add_rules(path_beneath_rules(read_only(config), read))?
+        .add_rules(path_beneath_rules(
+            ["/dev/input"],
+            read | AccessFs::IoctlDev,
+        ))?
+        .add_rules(path_beneath_rules(
+            ["/dev/null"],
+            AccessFs::ReadFile | AccessFs::WriteFile,
+        ))?
+        .add_rules(path_beneath_rules(writable, read_write))?
+        .restrict_self()?)
+}
+
+/// Allow only the syscalls in `SYSCALLS`, in every thread of the process.
+/// Call once startup is done.
+pub fn restrict_syscalls() -> Result<()> {
+    let mut rules: BTreeMap<i64, Vec<SeccompRule>> = SYSCALLS
+        .iter()
+        .map(|&syscall| (syscall, Vec::new()))
+        .collect();
+    rules.insert(
+        libc::SYS_socket,
+        SOCKET_FAMILIES
+            .iter()
+            .map(|&family| {
+                SeccompRule::new(vec![SeccompCondition::new(
+                    0,
+                    SeccompCmpArgLen::Dword,
+                    SeccompCmpOp::Eq,
+                    family as u64,
+                )?])
+            })
+            .collect::<Result<_, _>>()?,
+    );
+
+    let filter = SeccompFilter::new(
+        rules,
+        SeccompAction::Errno(libc::EPERM as u32),
+        SeccompAction::Allow,
+        std::env::consts::ARCH.try_into()?,
+    )?;
+    let program: seccompiler::BpfProgram = filter.try_into()?;
+
+    match seccompiler::apply_filter_all_threads(&program) {
+        Ok(()) => {
+            info!("Sandbox: seccomp syscall filter installed");
+            Ok(())
+        }
+        Err(e) => {
+            warn!(
+                "Sandbox: could not install the seccomp filter, syscalls are NOT restricted: {}",
+                e
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Run `probe` and report whether it was denied as `expect_denied` says.
+fn expect(description: &str, expect_denied: bool, probe: impl FnOnce() -> io::Result<()>) -> bool {
+    let result = probe();
+    let denied = matches!(&result, Err(e) if e.kind() == io::ErrorKind::PermissionDenied);
+    let ok = denied == expect_denied;
+    let outcome = match &result {
+        Ok(()) => "allowed".to_string(),
+        Err(e) => e.to_string(),
+    };
+    println!(
+        "{} {:<45} {}",
+        if ok { "✓" } else { "✗" },
+        description,
+        outcome
+    );
+    ok
+}
+
+/// `kunai sandbox check`: sandbox this process like the daemon, then check
+/// that what the daemon needs still works and that other access is denied.
+pub fn check() -> Result<()> {
+    let config = Config::load()?;
+    restrict_filesystem(&config)?;
+    restrict_syscalls()?;
+    println!();
+
+    let config_dir = config_dir()?;
+    let runtime_dir = instance::runtime_dir()?;
+    let home = dirs::home_dir().ok_or_else(|| anyhow::anyhow!("Could not find home directory"))?;
+
+    let results = [
+        expect("Read the config directory", false, || {
+            fs::read_dir(&config_dir).map(drop)
+        }),
+        expect("Write to the runtime directory", false, || {
+            let path = runtime_dir.join("sandbox-check");
+            fs::write(&path, "")?;
+            fs::remove_file(path)
+        }),
+        expect("Run programs from PATH", false, || {
+            Command::new("true").status().map(drop)
+        }),
+        expect("List the home directory", true, || {
+            fs::read_dir(&home).map(drop)
+        }),
+        expect("Write to the temp directory", true, || {
+            fs::write(std::env::temp_dir().join("kunai-sandbox-check"), "")
+        }),
+        expect("Open a network socket", true, || {
+            std::net::UdpSocket::bind("127.0.0.1:0").map(drop)
+        }),
+    ];
+
+    println!();
+    if results.iter().all(|ok| *ok) {
+        println!("✓ Sandbox works as expected");
+        Ok(())
+    } else {
+        anyhow::bail!("Sandbox check failed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_probe(dir: &std::path::Path) -> io::Result<()> {
+        let path = dir.join("probe");
+        fs::write(&path, "")?;
+        fs::remove_file(path)
+    }
+
+    #[test]
+    fn landlock_allows_only_the_daemon_dirs() {
+        let base = std::env::temp_dir().join(format!("kunai-sandbox-test-{}", std::process::id()));
+        let config_dir = base.join("config");
+        let runtime_dir = base.join("runtime");
+        fs::create_dir_all(&config_dir).unwrap();
+        fs::create_dir_all(&runtime_dir).unwrap();
+        let home = dirs::home_dir().unwrap();
+
+        // Landlock applies to the calling thread, so keep it off the test harness's
+        let writable = [config_dir.clone(), runtime_dir.clone()];
+        let probes = std::thread::spawn(move || {
+            let status = landlock(&Config::default(), &writable).unwrap();
+            if status.ruleset == RulesetStatus::NotEnforced {
+                return None;
+            }
+            Some((
+                fs::read_dir(&writable[0]).map(drop),
+                write_probe(&writable[0]),
+                write_probe(&writable[1]),
+                fs::read_dir(&home).map(drop),
+                write_probe(&std::env::temp_dir()),
+            ))
+        })
+        .join()
+        .unwrap();
+        fs::remove_dir_all(&base).unwrap();
+
+        let Some((read_config, write_config, write_runtime, read_home, write_temp)) = probes else {
+            eprintln!("Landlock is not supported here, skipping");
+            return;
+        };
+        read_config.unwrap();
+        write_config.unwrap();
+        write_runtime.unwrap();
+        assert_eq!(
+            read_home.unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            write_temp.unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn landlock_allows_usb_hotplug() {
+        use rusb::UsbContext;
+
+        if let Err(e) = rusb::Context::new() {
+            eprintln!("libusb is not usable here ({}), skipping", e);
+            return;
+        }
+        let writable = [std::env::temp_dir().join("kunai-sandbox-test-usb")];
+        let probe = std::thread::spawn(move || {
+            let status = landlock(&Config::default(), &writable).unwrap();
+            if status.ruleset == RulesetStatus::NotEnforced {
+                return None;
+            }
+            Some(rusb::Context::new().and_then(|context| context.devices().map(drop)))
+        })
+        .join()
+        .unwrap();
+
+        match probe {
+            Some(result) => result.unwrap(),
+            None => eprintln!("Landlock is not supported here, skipping"),
+        }
+    }
+}
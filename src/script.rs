@@ -0,0 +1,339 @@
+use std::{
+    cell::{
+        Cell,
+        RefCell,
+    },
+    collections::HashSet,
+    fs,
+    io::{
+        BufRead,
+        BufReader,
+        Write,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    rc::Rc,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use anyhow::Result;
+use chrono::Timelike;
+use rhai::{
+    AST,
+    Dynamic,
+    Engine,
+    EvalAltResult,
+    Map,
+    Scope,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use tracing::{
+    debug,
+    warn,
+};
+
+use crate::{
+    config::ScriptConfig,
+    instance,
+};
+
+/// Name of the function a rules script must define
+const ENTRY_POINT: &str = "decide";
+
+/// How long `process_running` answers from one scan of `/proc`
+const PROCESS_SCAN_TTL: Duration = Duration::from_secs(2);
+
+/// Size at which the recording is moved aside and started afresh
+const MAX_RECORDING_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Everything a rules script gets to see about one switch decision.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Context {
+    /// What triggered the decision: "keyboard", "window" or "workspace"
+    pub event:           String,
+    /// Active keyboard ("vid:pid") and its name
+    pub device_id:       Option<String>,
+    pub device_name:     Option<String>,
+    /// Keyboard that was active before it
+    pub previous_device: Option<String>,
+
+    pub current_layout: Option<u32>,
+    /// What kunai would do without the script; `None` means no change
+    pub default_layout: Option<u32>,
+
+    pub app_id:    Option<String>,
+    pub title:     Option<String>,
+    pub workspace: Option<String>,
+    pub output:    Option<String>,
+    pub language:  Option<String>,
+
+    pub timestamp_ms:         i64,
+    pub ms_since_last_switch: Option<u64>,
+}
+
+impl Context {
+    fn to_map(&self) -> Map {
+        fn opt<T: Into<Dynamic>>(value: Option<T>) -> Dynamic {
+            value.map(Into::into).unwrap_or(Dynamic::UNIT)
+        }
+
+        let local = chrono::DateTime::from_timestamp_millis(self.timestamp_ms)
+            .unwrap_or_default()
+            .with_timezone(&chrono::Local);
+
+        let mut map = Map::new();
+        map.insert("event".into(), self.event.clone().into());
+        map.insert("device_id".into(), opt(self.device_id.clone()));
+        map.insert("device_name".into(), opt(self.device_name.clone()));
+        map.insert("previous_device".into(), opt(self.previous_device.clone()));
+        map.insert(
+            "current_layout".into(),
+            opt(self.current_layout.map(i64::from)),
+        );
+        map.insert(
+            "default_layout".into(),
+            opt(self.default_layout.map(i64::from)),
+        );
+        map.insert("app_id".into(), opt(self.app_id.clone()));
+        map.insert("title".into(), opt(self.title.clone()));
+        map.insert("workspace".into(), opt(self.workspace.clone()));
+        map.insert("output".into(), opt(self.output.clone()));
+        map.insert("language".into(), opt(self.language.clone()));
+        map.insert("timestamp_ms".into(), self.timestamp_ms.into());
+        map.insert("hour".into(), i64::from(local.hour()).into());
+        map.insert("minute".into(), i64::from(local.minute()).into());
+        map.insert(
+            "ms_since_last_switch".into(),
+            opt(self.ms_since_last_switch.map(|ms| ms as i64)),
+        );
+        map
+    }
+}
+
+/// Names of running processes, so scripts can react to e.g. a key remapper
+/// being active. Scanning `/proc` takes long enough to blow the budget, so a
+/// scan is reused for `PROCESS_SCAN_TTL`.
+#[derive(Default)]
+struct Processes {
+    names:      HashSet<String>,
+    scanned_at: Option<Instant>,
+}
+
+impl Processes {
+    fn running(&mut self, name: &str) -> bool {
+        if self
+            .scanned_at
+            .is_none_or(|at| at.elapsed() >= PROCESS_SCAN_TTL)
+        {
+            self.names = scan_processes();
+            self.scanned_at = Some(Instant::now());
+        }
+        self.names.contains(name)
+    }
+}
+
+fn scan_processes() -> HashSet<String> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return HashSet::new();
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| fs::read_to_string(entry.path().join("comm")).ok())
+        .map(|comm| comm.trim().to_string())
+        .collect()
+}
+
+/// Decision contexts appended to `recording_path()`. Past
+/// `MAX_RECORDING_BYTES` the file becomes `previous_recording_path()`.
+struct Recording {
+    file: fs::File,
+    len:  u64,
+}
+
+impl Recording {
+    fn open() -> Result<Self> {
+        let path = recording_path()?;
+        debug!("Recording rule contexts to {}", path.display());
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let len = file.metadata()?.len();
+        Ok(Self { file, len })
+    }
+
+    fn write(&mut self, context: &Context) -> Result<()> {
+        if self.len >= MAX_RECORDING_BYTES {
+            fs::rename(recording_path()?, previous_recording_path()?)?;
+            *self = Self::open()?;
+        }
+        let line = serde_json::to_string(context)? + "\n";
+        self.file.write_all(line.as_bytes())?;
+        self.len += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// A compiled rules script, evaluated with a time budget per decision.
+pub struct RuleScript {
+    engine:   Engine,
+    ast:      AST,
+    deadline: Rc<Cell<Option<Instant>>>,
+    budget:   Duration,
+    record:   Option<Recording>,
+}
+
+impl RuleScript {
+    pub fn load(path: &Path, budget: Duration, record: bool) -> Result<Self> {
+        let deadline: Rc<Cell<Option<Instant>>> = Rc::new(Cell::new(None));
+
+        let mut engine = Engine::new();
+        let progress_deadline = deadline.clone();
+        engine.on_progress(move |_| match progress_deadline.get() {
+            Some(deadline) if Instant::now() > deadline => Some("time budget exceeded".into()),
+            _ => None,
+        });
+        let processes = RefCell::new(Processes::default());
+        engine.register_fn("process_running", move |name: &str| {
+            processes.borrow_mut().running(name)
+        });
+
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|e| anyhow::anyhow!("Failed to compile {}: {}", path.display(), e))?;
+
+        if !ast.iter_functions().any(|f| f.name == ENTRY_POINT) {
+            anyhow::bail!("{} must define `fn {}(ctx)`", path.display(), ENTRY_POINT);
+        }
+
+        let record = if record {
+            Some(Recording::open()?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            engine,
+            ast,
+            deadline,
+            budget,
+            record,
+        })
+    }
+
+    /// Run the script for `context`. `Ok(None)` means "no change".
+    pub fn evaluate(&mut self, context: &Context) -> Result<Option<u32>> {
+        if let Some(record) = &mut self.record
+            && let Err(e) = record.write(context)
+        {
+            warn!("Failed to record rule context: {}", e);
+        }
+
+        self.deadline.set(Some(Instant::now() + self.budget));
+        let result = self.engine.call_fn::<Dynamic>(
+            &mut Scope::new(),
+            &self.ast,
+            ENTRY_POINT,
+            (context.to_map(),),
+        );
+        self.deadline.set(None);
+
+        let value = result.map_err(|e| match *e {
+            EvalAltResult::ErrorTerminated(..) => {
+                anyhow::anyhow!("Rules script exceeded its {:?} budget", self.budget)
+            }
+            e => anyhow::anyhow!("Rules script failed: {}", e),
+        })?;
+        if value.is_unit() {
+            return Ok(None);
+        }
+        match value.as_int() {
+            Ok(layout) if layout >= 0 => Ok(Some(layout as u32)),
+            _ => anyhow::bail!(
+                "Rules script returned {} ({}), expected a layout index or ()",
+                value,
+                value.type_name()
+            ),
+        }
+    }
+}
+
+/// Where the daemon records decision contexts for `kunai rules test`.
+pub fn recording_path() -> Result<PathBuf> {
+    instance::runtime_path("events.jsonl")
+}
+
+/// Where the recording is moved once it grows too large.
+fn previous_recording_path() -> Result<PathBuf> {
+    instance::runtime_path("events.jsonl.1")
+}
+
+/// `kunai rules test`: replay recorded contexts through a script and print
+/// what it would decide for each.
+pub fn test(config: &ScriptConfig, script: Option<PathBuf>, events: Option<PathBuf>) -> Result<()> {
+    let script = script
+        .or_else(|| config.path.clone())
+        .ok_or_else(|| anyhow::anyhow!("No script given and no [script] path configured"))?;
+    let events = match events {
+        Some(path) => path,
+        None => recording_path()?,
+    };
+
+    let mut rules = RuleScript::load(&script, Duration::from_millis(config.budget_ms), false)?;
+    let file = fs::File::open(&events)
+        .map_err(|e| anyhow::anyhow!("Could not open {}: {}", events.display(), e))?;
+
+    let mut count = 0;
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let context: Context = match serde_json::from_str(&line) {
+            Ok(context) => context,
+            Err(e) => {
+                println!("{:>4}  invalid event: {}", i + 1, e);
+                continue;
+            }
+        };
+
+        let source = context
+            .device_name
+            .as_deref()
+            .or(context.app_id.as_deref())
+            .or(context.workspace.as_deref())
+            .unwrap_or("—");
+        let default = context
+            .default_layout
+            .map_or("no change".to_string(), |l| format!("layout {}", l));
+
+        let started = Instant::now();
+        let decision = match rules.evaluate(&context) {
+            Ok(Some(layout)) => format!("layout {}", layout),
+            Ok(None) => "no change".to_string(),
+            Err(e) => format!("error: {}", e),
+        };
+
+        println!(
+            "{:>4}  {:<9} {:<30} default: {:<10} → {}  ({} µs)",
+            i + 1,
+            context.event,
+            source,
+            default,
+            decision,
+            started.elapsed().as_micros()
+        );
+        count += 1;
+    }
+
+    println!("\n{} event(s) evaluated with {}", count, script.display());
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use std::sync::{
+    Arc,
+    Condvar,
+    Mutex,
+};
+
+use anyhow::Result;
+use tokio::sync::mpsc;
+use tracing::trace;
+
+use crate::niri;
+
+/// A layout switch for the worker to carry out
+#[derive(Debug, Clone, Copy)]
+pub struct Job {
+    pub seq:           u64,
+    pub layout:        u32,
+    /// Requested because a keyboard became active, so the policy has to be
+    /// told if it fails
+    pub from_keyboard: bool,
+}
+
+/// Result of a job, sent back to the daemon loop
+pub struct Outcome {
+    pub job:    Job,
+    /// Layout indices niri passed through, see `niri::switch_to_layout`
+    pub result: Result<Vec<u32>>,
+}
+
+/// Holds at most one job: a new request replaces one that hasn't started.
+#[derive(Default)]
+struct Mailbox {
+    job:   Mutex<Option<Job>>,
+    ready: Condvar,
+}
+
+/// Runs niri switches on a dedicated thread, so a slow backend never holds
+/// up key presses, hotplug handling or shutdown. Only the latest requested
+/// target is applied; anything requested while a switch runs collapses into
+/// one switch afterwards.
+pub struct Switcher {
+    mailbox:  Arc<Mailbox>,
+    next_seq: u64,
+}
+
+impl Switcher {
+    pub fn spawn(outcome_tx: mpsc::UnboundedSender<Outcome>) -> Result<Self> {
+        let mailbox = Arc::new(Mailbox::default());
+        let worker_mailbox = mailbox.clone();
+        std::thread::Builder::new()
+            .name("kunai-switch".to_string())
+            .spawn(move || run(&worker_mailbox, &outcome_tx))?;
+
+        Ok(Self {
+            mailbox,
+            next_seq: 0,
+        })
+    }
+
+    /// Ask for `layout`, replacing any switch that hasn't started yet.
+    /// Returns the job's sequence number.
+    pub fn request(&mut self, layout: u32, from_keyboard: bool) -> u64 {
+        self.next_seq += 1;
+        let job = Job {
+            seq: self.next_seq,
+            layout,
+            from_keyboard,
+        };
+
+        let mut slot = self.mailbox.job.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(superseded) = slot.replace(job) {
+            trace!(
+                "Switch to layout {} superseded by layout {}",
+                superseded.layout, layout
+            );
+        }
+        self.mailbox.ready.notify_one();
+        job.seq
+    }
+}
+
+fn run(mailbox: &Mailbox, outcome_tx: &mpsc::UnboundedSender<Outcome>) {
+    loop {
+        let job = {
+            let mut slot = mailbox.job.lock().unwrap_or_else(|e| e.into_inner());
+            loop {
+                if let Some(job) = slot.take() {
+                    break job;
+                }
+                slot = mailbox.ready.wait(slot).unwrap_or_else(|e| e.into_inner());
+            }
+        };
+
+        let result = niri::switch_to_layout(job.layout);
+        if outcome_tx.send(Outcome { job, result }).is_err() {
+            // The daemon loop is gone
+            break;
+        }
+    }
+}
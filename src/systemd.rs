@@ -0,0 +1,436 @@
+use std::{
+    fs,
+    os::{
+        fd::AsFd,
+        linux::net::SocketAddrExt,
+        unix::{
+            fs::{
+                MetadataExt,
+                PermissionsExt,
+            },
+            net::{
+                SocketAddr,
+                UnixDatagram,
+            },
+        },
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    process::Command,
+    time::Duration,
+};
+
+use anyhow::Result;
+use tracing::{
+    debug,
+    warn,
+};
+
+use crate::instance;
+
+/// Client for the systemd notification protocol (`sd_notify(3)`).
+///
+/// Only exists when the daemon was started by systemd with `NOTIFY_SOCKET`
+/// set, so callers can treat `None` as "not running under systemd".
+pub struct Notifier {
+    socket: UnixDatagram,
+    addr:   SocketAddr,
+}
+
+impl Notifier {
+    pub fn from_env() -> Option<Self> {
+        Self::new(&std::env::var("NOTIFY_SOCKET").ok()?)
+    }
+
+    /// Notify the socket at `path`, in `NOTIFY_SOCKET` syntax.
+    fn new(path: &str) -> Option<Self> {
+        // A leading '@' denotes a Linux abstract socket
+        let addr = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+            None => SocketAddr::from_pathname(path),
+        };
+        let addr = match addr {
+            Ok(addr) => addr,
+            Err(e) => {
+                warn!("Invalid NOTIFY_SOCKET {:?}: {}", path, e);
+                return None;
+            }
+        };
+
+        match UnixDatagram::unbound() {
+            Ok(socket) => Some(Self { socket, addr }),
+            Err(e) => {
+                warn!("Failed to create notify socket: {}", e);
+                None
+            }
+        }
+    }
+
+    fn notify(&self, state: &str) {
+        if let Err(e) = self.socket.send_to_addr(state.as_bytes(), &self.addr) {
+            warn!("sd_notify({:?}) failed: {}", state, e);
+        }
+    }
+
+    pub fn ready(&self, status: &str) {
+        debug!("Notifying systemd: ready");
+        self.notify(&format!("READY=1\nSTATUS={}", status));
+    }
+
+    pub fn status(&self, status: &str) {
+        self.notify(&format!("STATUS={}", status));
+    }
+
+    pub fn watchdog(&self) {
+        self.notify("WATCHDOG=1");
+    }
+
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+}
+
+/// How often to ping the watchdog: half of `WatchdogSec=`, as recommended by
+/// `sd_watchdog_enabled(3)`. `None` if the watchdog is not enabled for us.
+pub fn watchdog_interval() -> Option<Duration> {
+    parse_watchdog(
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+    )
+}
+
+/// `watchdog_interval` for the given `WATCHDOG_PID` and `WATCHDOG_USEC`.
+fn parse_watchdog(pid: Option<&str>, usec: Option<&str>) -> Option<Duration> {
+    if let Some(pid) = pid
+        && pid.parse::<u32>().ok() != Some(std::process::id())
+    {
+        return None;
+    }
+
+    let usec: u64 = usec?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+/// True if stderr is connected to the journal, i.e. `JOURNAL_STREAM` matches
+/// the device and inode of our stderr (see `systemd.exec(5)`).
+pub fn stderr_is_journal() -> bool {
+    let Ok(stream) = std::env::var("JOURNAL_STREAM") else {
+        return false;
+    };
+    let Some((dev, ino)) = stream.split_once(':') else {
+        return false;
+    };
+
+    match nix::sys::stat::fstat(std::io::stderr().as_fd()) {
+        Ok(stat) => {
+            dev.parse::<u64>().ok() == Some(stat.st_dev)
+                && ino.parse::<u64>().ok() == Some(stat.st_ino)
+        }
+        Err(_) => false,
+    }
+}
+
+fn unit_name() -> String {
+    match instance::name() {
+        instance::DEFAULT_INSTANCE => "kunai.service".to_string(),
+        name => format!("kunai-{}.service", name),
+    }
+}
+
+fn unit_path() -> Result<PathBuf> {
+    Ok(dirs::config_dir()
+        .ok_or_else(|| anyhow::anyhow!("Could not find config directory"))?
+        .join("systemd")
+        .join("user")
+        .join(unit_name()))
+}
+
+fn unit_file() -> Result<String> {
+    let exe = std::env::current_exe()?;
+    let mut exec_start = format!("{} daemon", exe.display());
+    if instance::name() != instance::DEFAULT_INSTANCE {
+        exec_start.push_str(&format!(" --instance {}", instance::name()));
+    }
+
+    Ok(format!(
+        "[Unit]
+Description=kunai per-keyboard layout switcher
+Documentation=https://github.com/mikkurogue/kunai
+PartOf=graphical-session.target
+After=graphical-session.target
+
+[Service]
+Type=notify
+NotifyAccess=main
+ExecStart={}
+Restart=on-failure
+RestartSec=2
+WatchdogSec=30
+
+[Install]
+WantedBy=graphical-session.target
+",
+        exec_start
+    ))
+}
+
+const HELPER_UNIT_NAME: &str = "kunai-helper.service";
+const HELPER_UNIT_PATH: &str = "/etc/systemd/system/kunai-helper.service";
+/// The helper runs as root, so it runs its own copy of kunai that only root
+/// can replace, not the binary `sudo` was given (e.g. in ~/.cargo/bin)
+const HELPER_BINARY: &str = "/usr/local/libexec/kunai-helper";
+
+fn helper_unit_file(user: &str) -> String {
+    format!(
+        "[Unit]
+Description=kunai input helper
+Documentation=https://github.com/mikkurogue/kunai
+
+[Service]
+ExecStart={} helper --allow-user {} --socket {}
+Restart=on-failure
+RestartSec=2
+RuntimeDirectory=kunai
+RuntimeDirectoryMode=0755
+DevicePolicy=closed
+DeviceAllow=char-input r
+NoNewPrivileges=yes
+ProtectSystem=strict
+ProtectHome=yes
+PrivateTmp=yes
+RestrictAddressFamilies=AF_UNIX AF_NETLINK
+
+[Install]
+WantedBy=multi-user.target
+",
+        HELPER_BINARY,
+        user,
+        crate::helper::DEFAULT_SOCKET
+    )
+}
+
+/// Copy the running binary to `HELPER_BINARY`, replacing any older copy in
+/// one step.
+fn install_helper_binary() -> Result<()> {
+    let exe = std::env::current_exe()?;
+    let target = Path::new(HELPER_BINARY);
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let tmp = target.with_extension("new");
+    fs::copy(&exe, &tmp)?;
+    fs::set_permissions(&tmp, fs::Permissions::from_mode(0o755))?;
+    fs::rename(&tmp, target)?;
+    Ok(())
+}
+
+/// Make sure only root can change `path`: it and every directory above it
+/// must be owned by root and not writable by group or others.
+fn check_root_owned(path: &Path) -> Result<()> {
+    for path in path.ancestors() {
+        let metadata = fs::metadata(path)
+            .map_err(|e| anyhow::anyhow!("Cannot check {}: {}", path.display(), e))?;
+        if metadata.uid() != 0 || metadata.mode() & 0o022 != 0 {
+            anyhow::bail!(
+                "{} must be owned by root and writable only by root (owner {}, mode {:o})",
+                path.display(),
+                metadata.uid(),
+                metadata.mode() & 0o7777
+            );
+        }
+    }
+    Ok(())
+}
+
+fn systemctl(args: &[&str]) -> Result<()> {
+    systemctl_in("--user", args)
+}
+
+fn systemctl_in(scope: &str, args: &[&str]) -> Result<()> {
+    let status = Command::new("systemctl").arg(scope).args(args).status()?;
+    if !status.success() {
+        anyhow::bail!("systemctl {} {} failed ({})", scope, args.join(" "), status);
+    }
+    Ok(())
+}
+
+/// Write the user unit file, then reload systemd and enable the service.
+pub fn install(dry_run: bool) -> Result<()> {
+    let path = unit_path()?;
+    let unit = unit_file()?;
+
+    if dry_run {
+        println!("Would write {}:\n", path.display());
+        println!("{}", unit);
+        println!("Would run: systemctl --user enable --now {}", unit_name());
+        return Ok(());
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&path, unit)?;
+    println!("✓ Wrote {}", path.display());
+
+    systemctl(&["daemon-reload"])?;
+    systemctl(&["enable", "--now", &unit_name()])?;
+    println!("✓ Enabled and started {}", unit_name());
+    println!("\nRemove any `spawn-at-startup \"kunai\" \"daemon\"` line from your niri config.");
+
+    Ok(())
+}
+
+/// Disable the service and remove its unit file.
+pub fn uninstall() -> Result<()> {
+    let path = unit_path()?;
+    if !path.exists() {
+        println!("{} is not installed", unit_name());
+        return Ok(());
+    }
+
+    systemctl(&["disable", "--now", &unit_name()])?;
+    fs::remove_file(&path)?;
+    systemctl(&["daemon-reload"])?;
+    println!("✓ Removed {}", path.display());
+
+    Ok(())
+}
+
+/// Write the input helper's system unit, allowing the user who ran sudo,
+/// then enable and start it.
+pub fn install_helper(dry_run: bool) -> Result<()> {
+    let user = match std::env::var("SUDO_USER") {
+        Ok(user) => user,
+        Err(_) => nix::unistd::User::from_uid(nix::unistd::getuid())?
+            .map(|user| user.name)
+            .ok_or_else(|| anyhow::anyhow!("Could not determine the current user"))?,
+    };
+    let unit = helper_unit_file(&user);
+
+    if dry_run {
+        println!(
+            "Would copy {} to {}",
+            std::env::current_exe()?.display(),
+            HELPER_BINARY
+        );
+        println!("Would write {}:\n", HELPER_UNIT_PATH);
+        println!("{}", unit);
+        println!("Would run: systemctl enable --now {}", HELPER_UNIT_NAME);
+        return Ok(());
+    }
+
+    if !nix::unistd::geteuid().is_root() {
+        anyhow::bail!("Installing the input helper needs root; run it with sudo");
+    }
+    if user == "root" {
+        anyhow::bail!("Run this with sudo from the account that runs kunai, not as root");
+    }
+
+    install_helper_binary()?;
+    check_root_owned(Path::new(HELPER_BINARY))?;
+    println!("✓ Installed {}", HELPER_BINARY);
+
+    fs::write(HELPER_UNIT_PATH, unit)?;
+    println!("✓ Wrote {}", HELPER_UNIT_PATH);
+
+    systemctl_in("--system", &["daemon-reload"])?;
+    systemctl_in("--system", &["enable", "--now", HELPER_UNIT_NAME])?;
+    println!("✓ Enabled and started {} for {}", HELPER_UNIT_NAME, user);
+    println!("\nAdd this to your config.toml, then restart the daemon:");
+    println!("  input_helper = \"{}\"", crate::helper::DEFAULT_SOCKET);
+    println!("\nRun this again after upgrading kunai to update the helper.");
+
+    Ok(())
+}
+
+/// Disable the input helper and remove its system unit.
+pub fn uninstall_helper() -> Result<()> {
+    if !std::path::Path::new(HELPER_UNIT_PATH).exists() {
+        println!("{} is not installed", HELPER_UNIT_NAME);
+        return Ok(());
+    }
+
+    systemctl_in("--system", &["disable", "--now", HELPER_UNIT_NAME])?;
+    fs::remove_file(HELPER_UNIT_PATH)?;
+    systemctl_in("--system", &["daemon-reload"])?;
+    println!("✓ Removed {}", HELPER_UNIT_PATH);
+    match fs::remove_file(HELPER_BINARY) {
+        Ok(()) => println!("✓ Removed {}", HELPER_BINARY),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => warn!("Failed to remove {}: {}", HELPER_BINARY, e),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receive(socket: &UnixDatagram) -> String {
+        let mut buf = [0; 256];
+        let len = socket.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..len].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn notifier_sends_states() {
+        let dir = std::env::temp_dir().join(format!("kunai-notify-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify.sock");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let notifier = Notifier::new(path.to_str().unwrap()).unwrap();
+        notifier.ready("Monitoring 2 keyboard(s)");
+        assert_eq!(receive(&socket), "READY=1\nSTATUS=Monitoring 2 keyboard(s)");
+        notifier.status("Paused");
+        assert_eq!(receive(&socket), "STATUS=Paused");
+        notifier.watchdog();
+        assert_eq!(receive(&socket), "WATCHDOG=1");
+        notifier.stopping();
+        assert_eq!(receive(&socket), "STOPPING=1");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn notifier_supports_abstract_sockets() {
+        let name = format!("kunai-notify-test-{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let socket = UnixDatagram::bind_addr(&addr).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        Notifier::new(&format!("@{}", name)).unwrap().watchdog();
+        assert_eq!(receive(&socket), "WATCHDOG=1");
+    }
+
+    #[test]
+    fn watchdog_interval_is_half_the_timeout() {
+        let pid = std::process::id().to_string();
+        assert_eq!(
+            parse_watchdog(None, Some("10000000")),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            parse_watchdog(Some(&pid), Some("10000000")),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(parse_watchdog(None, Some("0")), None);
+        assert_eq!(parse_watchdog(None, None), None);
+    }
+
+    #[test]
+    fn watchdog_for_another_process_is_ignored() {
+        let other = (std::process::id() + 1).to_string();
+        assert_eq!(parse_watchdog(Some(&other), Some("10000000")), None);
+        assert_eq!(parse_watchdog(Some("not a pid"), Some("10000000")), None);
+    }
+}
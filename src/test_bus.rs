@@ -0,0 +1,60 @@
+use std::{
+    io::{
+        BufRead,
+        BufReader,
+    },
+    process::{
+        Child,
+        Command,
+        Stdio,
+    },
+};
+
+use zbus::{
+    Address,
+    Connection,
+    connection,
+};
+
+/// A private `dbus-daemon` for tests, killed on drop
+pub struct TestBus {
+    daemon:  Child,
+    address: String,
+}
+
+impl TestBus {
+    /// Start a bus with the session policy; `None` if `dbus-daemon` is not
+    /// installed.
+    pub fn start() -> Option<Self> {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--print-address", "--nofork"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .ok()?;
+        let mut address = String::new();
+        let stdout = daemon.stdout.take().expect("stdout is piped");
+        BufReader::new(stdout).read_line(&mut address).ok()?;
+        Some(Self {
+            daemon,
+            address: address.trim().to_string(),
+        })
+    }
+
+    /// A builder for a new connection to the bus
+    pub fn builder(&self) -> connection::Builder<'static> {
+        let address: Address = self.address.parse().expect("dbus-daemon prints an address");
+        connection::Builder::address(address).unwrap()
+    }
+
+    pub async fn connect(&self) -> Connection {
+        self.builder().build().await.unwrap()
+    }
+}
+
+impl Drop for TestBus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
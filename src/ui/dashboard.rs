@@ -44,7 +44,6 @@ const MAX_LINES: usize = 10_000;
 struct DashboardState {
     lines:         VecDeque<String>,
     log_path:      PathBuf,
-    pid_path:      PathBuf,
     last_mtime:    Option<SystemTime>,
     log_size:      Option<u64>,
     scroll_offset: usize,
@@ -59,20 +58,18 @@ pub fn run() -> Result<()> {
         .join("kunai");
 
     let log_path = config_dir.join("daemon.log");
-    let pid_path = config_dir.join("daemon.pid");
 
     let mut terminal =
         ratatui::try_init().map_err(|e| anyhow::anyhow!("Dashboard requires a terminal: {}", e))?;
-    let result = run_dashboard(&mut terminal, &log_path, &pid_path);
+    let result = run_dashboard(&mut terminal, &log_path);
     let _ = ratatui::try_restore();
     result
 }
 
-fn run_dashboard(terminal: &mut DefaultTerminal, log_path: &Path, pid_path: &Path) -> Result<()> {
+fn run_dashboard(terminal: &mut DefaultTerminal, log_path: &Path) -> Result<()> {
     let mut state = DashboardState {
         lines:         VecDeque::new(),
         log_path:      log_path.to_owned(),
-        pid_path:      pid_path.to_owned(),
         last_mtime:    None,
         log_size:      None,
         scroll_offset: 0,
@@ -164,30 +161,16 @@ fn load_log(state: &mut DashboardState) -> Result<()> {
 }
 
 fn read_pid(state: &mut DashboardState) {
-    let content = match fs::read_to_string(&state.pid_path) {
-        Ok(c) => c,
-        Err(_) => {
-            state.pid = None;
-            state.running = false;
-            return;
+    match crate::instance::running_pid() {
+        Ok(Some(pid)) => {
+            state.pid = Some(pid.as_raw());
+            state.running = true;
         }
-    };
-
-    let pid: i32 = match content.trim().parse() {
-        Ok(p) => p,
-        Err(_) => {
+        _ => {
             state.pid = None;
             state.running = false;
-            return;
         }
-    };
-
-    state.pid = Some(pid);
-    state.running = is_process_alive(pid);
-}
-
-fn is_process_alive(pid: i32) -> bool {
-    nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid), None).is_ok()
+    }
 }
 
 fn draw_dashboard(frame: &mut Frame, state: &DashboardState) {
@@ -61,6 +61,14 @@ enum Mode {
 pub fn run(dry_run: bool) -> Result<()> {
     let keyboards = input::list_keyboards()?;
     let layouts = niri::get_layouts()?;
+    // Keep any settings that aren't edited by the wizard. Refuse to run if
+    // they can't be read rather than overwrite them with defaults.
+    let existing = Config::load().map_err(|e| {
+        anyhow::anyhow!(
+            "Could not load the existing config, fix or remove it first: {}",
+            e
+        )
+    })?;
 
     if keyboards.is_empty() {
         anyhow::bail!("No keyboards detected. Check permissions.");
@@ -86,7 +94,7 @@ pub fn run(dry_run: bool) -> Result<()> {
     crate::ui::clear_inline(viewport_height);
 
     if saved {
-        let config = build_config(&state, &keyboards, &layouts);
+        let config = build_config(existing, &state, &keyboards, &layouts);
         if dry_run {
             println!("\nDry-run");
             println!("Would save to ~/.config/kunai/config.toml:\n");
@@ -120,10 +128,8 @@ fn run_loop(
                     KeyCode::Up | KeyCode::Char('k') => {
                         state.row = state.row.saturating_sub(1);
                     }
-                    KeyCode::Down | KeyCode::Char('j') => {
-                        if state.row + 1 < keyboards.len() {
-                            state.row += 1;
-                        }
+                    KeyCode::Down | KeyCode::Char('j') if state.row + 1 < keyboards.len() => {
+                        state.row += 1;
                     }
                     KeyCode::Enter => {
                         state.layout_cursor = state.assignments[state.row].unwrap_or(0);
@@ -139,10 +145,10 @@ fn run_loop(
                     KeyCode::Up | KeyCode::Char('k') => {
                         state.layout_cursor = state.layout_cursor.saturating_sub(1);
                     }
-                    KeyCode::Down | KeyCode::Char('j') => {
-                        if state.layout_cursor + 1 < layouts.len() {
-                            state.layout_cursor += 1;
-                        }
+                    KeyCode::Down | KeyCode::Char('j')
+                        if state.layout_cursor + 1 < layouts.len() =>
+                    {
+                        state.layout_cursor += 1;
                     }
                     KeyCode::Enter => {
                         state.assignments[keyboard_idx] = Some(state.layout_cursor);
@@ -301,23 +307,38 @@ fn draw_layout_popup(
     frame.render_widget(list, popup_area);
 }
 
-fn build_config(state: &SetupState, keyboards: &[input::Keyboard], _layouts: &[String]) -> Config {
+fn build_config(
+    existing: Config,
+    state: &SetupState,
+    keyboards: &[input::Keyboard],
+    _layouts: &[String],
+) -> Config {
     let kb_configs: Vec<KeyboardConfig> = keyboards
         .iter()
         .enumerate()
         .filter_map(|(i, kb)| {
             let layout_index = state.assignments[i]?;
             let name = kb.name.clone();
+            let vendor_id = format!("{:04x}", kb.vendor_id);
+            let product_id = format!("{:04x}", kb.product_id);
+            let previous = existing
+                .keyboards
+                .iter()
+                .find(|k| k.vendor_id == vendor_id && k.product_id == product_id)
+                .cloned()
+                .unwrap_or_default();
             Some(KeyboardConfig {
                 name,
-                vendor_id: format!("{:04x}", kb.vendor_id),
-                product_id: format!("{:04x}", kb.product_id),
+                vendor_id,
+                product_id,
                 layout_index: layout_index as u32,
+                ..previous
             })
         })
         .collect();
 
     Config {
         keyboards: kb_configs,
+        ..existing
     }
 }
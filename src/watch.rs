@@ -0,0 +1,138 @@
+use std::{
+    io::Write,
+    time::Duration,
+};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde_json::json;
+use tracing::debug;
+
+use crate::ipc::{
+    self,
+    Snapshot,
+};
+
+/// How long to wait before reconnecting to a daemon that is not running
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Format {
+    /// One human-readable line
+    Text,
+    /// The daemon's snapshot as JSON, `null` while it is not running
+    Json,
+    /// Waybar custom module JSON (`"return-type": "json"`)
+    Waybar,
+}
+
+/// `kunai watch`: print a line for the daemon's state and another on every
+/// change. Keeps running across daemon restarts, so a bar can start it once;
+/// exits when its output is closed.
+pub fn run(format: Format) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    let mut offline_shown = false;
+
+    loop {
+        let mut output_closed = false;
+        let result = ipc::watch(|snapshot| {
+            offline_shown = false;
+            emit(&mut stdout, format, Some(snapshot)).inspect_err(|_| output_closed = true)?;
+            Ok(())
+        });
+        if output_closed {
+            return Ok(());
+        }
+        if let Err(e) = result {
+            debug!("Not watching the daemon: {}", e);
+        }
+
+        if !offline_shown {
+            if emit(&mut stdout, format, None).is_err() {
+                return Ok(());
+            }
+            offline_shown = true;
+        }
+        std::thread::sleep(RETRY_DELAY);
+    }
+}
+
+/// Write one line for `snapshot`, or for a daemon that is not running.
+fn emit(out: &mut impl Write, format: Format, snapshot: Option<&Snapshot>) -> std::io::Result<()> {
+    let line = match (format, snapshot) {
+        (Format::Text, Some(snapshot)) => text(snapshot),
+        (Format::Text, None) => "kunai is not running".to_string(),
+        (Format::Json, snapshot) => serde_json::to_string(&snapshot)?,
+        (Format::Waybar, snapshot) => waybar(snapshot).to_string(),
+    };
+    writeln!(out, "{}", line)?;
+    out.flush()
+}
+
+/// The layout's name, falling back to its index.
+fn layout_label(snapshot: &Snapshot) -> String {
+    match (&snapshot.layout, snapshot.layout_index) {
+        (Some(name), _) => name.clone(),
+        (None, Some(index)) => index.to_string(),
+        (None, None) => "?".to_string(),
+    }
+}
+
+fn text(snapshot: &Snapshot) -> String {
+    let mut parts = vec![layout_label(snapshot)];
+    if let Some(active) = &snapshot.active {
+        parts.push(active.name.clone());
+    }
+    parts.push(match snapshot.keyboards.len() {
+        1 => "1 keyboard".to_string(),
+        n => format!("{} keyboards", n),
+    });
+    if snapshot.paused {
+        parts.push("paused".to_string());
+    }
+    parts.join(" · ")
+}
+
+fn waybar(snapshot: Option<&Snapshot>) -> serde_json::Value {
+    let Some(snapshot) = snapshot else {
+        return json!({
+            "text": "",
+            "tooltip": "kunai is not running",
+            "class": "stopped",
+        });
+    };
+
+    let layout = layout_label(snapshot);
+    let mut tooltip = vec![match &snapshot.active {
+        Some(active) => format!("Keyboard: {} ({})", active.name, active.device_id),
+        None => "Keyboard: —".to_string(),
+    }];
+    tooltip.push(format!("Layout: {}", layout));
+    let connected: Vec<&str> = snapshot
+        .keyboards
+        .iter()
+        .map(|kb| kb.name.as_str())
+        .collect();
+    tooltip.push(if connected.is_empty() {
+        "Connected: none".to_string()
+    } else {
+        format!("Connected: {}", connected.join(", "))
+    });
+    if snapshot.paused {
+        tooltip.push("Switching paused".to_string());
+    }
+
+    // Waybar renders both as Pango markup
+    json!({
+        "text": escape_markup(&layout),
+        "alt": layout,
+        "tooltip": escape_markup(&tooltip.join("\n")),
+        "class": if snapshot.paused { "paused" } else { "active" },
+    })
+}
+
+fn escape_markup(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}